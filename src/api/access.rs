@@ -0,0 +1,93 @@
+use std::env;
+use std::sync::LazyLock;
+use dotenvy::dotenv;
+
+// What a handler is about to do on behalf of the token holder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Account,
+    WorkspaceRead,
+    WorkspaceWrite,
+    NodeWrite,
+    Chat
+}
+
+impl Access {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Access::Account => "account",
+            Access::WorkspaceRead => "workspace:read",
+            Access::WorkspaceWrite => "workspace:write",
+            Access::NodeWrite => "node:write",
+            Access::Chat => "chat"
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Access> {
+        match value.trim() {
+            "account" => Some(Access::Account),
+            "workspace:read" => Some(Access::WorkspaceRead),
+            "workspace:write" => Some(Access::WorkspaceWrite),
+            "node:write" => Some(Access::NodeWrite),
+            "chat" => Some(Access::Chat),
+            _ => None
+        }
+    }
+}
+
+// Accesses denied to users who have not verified their email yet.
+// Configured as a comma separated list in UNVERIFIED_RESTRICTIONS, AI chat only by default.
+// Account access is never restricted, otherwise unverified users could not resend their mail.
+static UNVERIFIED_RESTRICTIONS: LazyLock<Vec<Access>> = LazyLock::new(|| {
+    dotenv().expect("Failed to load environment variables!");
+    env::var("UNVERIFIED_RESTRICTIONS")
+        .unwrap_or("chat".into())
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| Access::parse(s).unwrap_or_else(|| panic!("Unknown access <{}> in UNVERIFIED_RESTRICTIONS!", s)))
+        .filter(|access| *access != Access::Account)
+        .collect()
+});
+
+pub fn load_restrictions() {
+    LazyLock::force(&UNVERIFIED_RESTRICTIONS);
+}
+
+pub fn allowed_unverified(access: Access) -> bool {
+    !UNVERIFIED_RESTRICTIONS.contains(&access)
+}
+
+// The access an AuthUser extractor demands, picked by type: AuthUser<scope::WorkspaceRead>
+pub trait Scope: Send + Sync {
+    const ACCESS: Access;
+}
+
+pub mod scope {
+    use super::{Access, Scope};
+
+    pub struct Account;
+    pub struct WorkspaceRead;
+    pub struct WorkspaceWrite;
+    pub struct NodeWrite;
+    pub struct Chat;
+
+    impl Scope for Account {
+        const ACCESS: Access = Access::Account;
+    }
+
+    impl Scope for WorkspaceRead {
+        const ACCESS: Access = Access::WorkspaceRead;
+    }
+
+    impl Scope for WorkspaceWrite {
+        const ACCESS: Access = Access::WorkspaceWrite;
+    }
+
+    impl Scope for NodeWrite {
+        const ACCESS: Access = Access::NodeWrite;
+    }
+
+    impl Scope for Chat {
+        const ACCESS: Access = Access::Chat;
+    }
+}
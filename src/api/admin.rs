@@ -0,0 +1,261 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use crate::auth::role::Role;
+use crate::debug::{log, LogType::HTTP};
+use super::access::scope;
+use super::api::ApiError;
+use super::audit::{self, AuditEvent, AuditQuery, AuditRecord};
+use super::auth_user::AuthUser;
+use super::client::ClientInfo;
+
+/*
+
+    ADMIN API
+
+    Mounted under /admin behind require_role(Role::Admin), which leaves the admin making the
+    request in the extensions. AI usage is counted from the stored chat messages: every
+    reply of the assistant is one completion request made on the user's behalf.
+
+*/
+
+type Admin = Extension<AuthUser<scope::Account>>;
+
+#[derive(Deserialize)]
+pub struct ListUsersQuery {
+    // Matched against the username and email, case-insensitively
+    search: Option<String>,
+    role: Option<Role>,
+    limit: Option<i64>,
+    offset: Option<i64>
+}
+
+#[derive(Serialize)]
+pub struct UserSummary {
+    id: i32,
+    username: String,
+    email: String,
+    role: String,
+    email_verified: bool,
+    disabled_at: Option<DateTime<Utc>>,
+    created: Option<DateTime<Utc>>,
+    workspaces: i64,
+    ai_messages: i64
+}
+
+#[derive(Serialize)]
+pub struct UserUsage {
+    user_id: i32,
+    workspaces: i64,
+    nodes: i64,
+    resolved_nodes: i64,
+    chats: i64,
+    user_messages: i64,
+    ai_messages: i64,
+    ai_messages_last_30_days: i64,
+    last_ai_message: Option<NaiveDateTime>
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    users: i64,
+    students: i64,
+    teachers: i64,
+    admins: i64,
+    disabled_users: i64,
+    workspaces: i64,
+    ai_messages: i64,
+    ai_messages_last_30_days: i64
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleRequest {
+    role: Role
+}
+
+pub async fn list_users(
+    State(db): State<Pool<Postgres>>,
+    Extension(admin): Admin,
+    Query(query): Query<ListUsersQuery>
+) -> Result<Json<Vec<UserSummary>>, ApiError> {
+    log(HTTP, &format!("Admin <{}> requested FETCH users", admin.user_id));
+    let users = sqlx::query_as!(
+        UserSummary,
+        r#"SELECT users.id, users.username, users.email, users.role, users.email_verified, users.disabled_at, users.created,
+            (SELECT COUNT(*) FROM workspaces WHERE workspaces.user_id = users.id) AS "workspaces!",
+            (SELECT COUNT(*) FROM messages
+            JOIN chats ON chats.id = messages.chat_id
+            JOIN workspaces ON workspaces.id = chats.workspace_id
+            WHERE workspaces.user_id = users.id AND NOT messages.is_user) AS "ai_messages!"
+        FROM users
+        WHERE ($1::TEXT IS NULL OR strpos(lower(users.username), lower($1)) > 0 OR strpos(users.email, lower($1)) > 0)
+            AND ($2::TEXT IS NULL OR users.role = $2)
+        ORDER BY users.id
+        LIMIT $3 OFFSET $4"#,
+        query.search.as_deref().map(str::trim),
+        query.role.map(|role| role.as_str()),
+        query.limit.unwrap_or(50).clamp(1, 200),
+        query.offset.unwrap_or(0).max(0)
+    )
+    .fetch_all(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(Json(users))
+}
+
+pub async fn user_usage(
+    State(db): State<Pool<Postgres>>,
+    Extension(admin): Admin,
+    Path(id): Path<i32>
+) -> Result<Json<UserUsage>, ApiError> {
+    log(HTTP, &format!("Admin <{}> requested FETCH usage of UserID <{}>", admin.user_id, id));
+    let usage = sqlx::query_as!(
+        UserUsage,
+        r#"SELECT users.id AS user_id,
+            (SELECT COUNT(*) FROM workspaces WHERE user_id = users.id) AS "workspaces!",
+            (SELECT COUNT(*) FROM nodes JOIN workspaces ON workspaces.id = nodes.workspace_id
+            WHERE workspaces.user_id = users.id) AS "nodes!",
+            (SELECT COUNT(*) FROM nodes JOIN workspaces ON workspaces.id = nodes.workspace_id
+            WHERE workspaces.user_id = users.id AND nodes.resolved) AS "resolved_nodes!",
+            (SELECT COUNT(*) FROM chats JOIN workspaces ON workspaces.id = chats.workspace_id
+            WHERE workspaces.user_id = users.id) AS "chats!",
+            usage.user_messages AS "user_messages!",
+            usage.ai_messages AS "ai_messages!",
+            usage.ai_messages_last_30_days AS "ai_messages_last_30_days!",
+            usage.last_ai_message
+        FROM users,
+        LATERAL (
+            SELECT
+                COUNT(*) FILTER (WHERE messages.is_user) AS user_messages,
+                COUNT(*) FILTER (WHERE NOT messages.is_user) AS ai_messages,
+                COUNT(*) FILTER (WHERE NOT messages.is_user AND messages.sent_at > now() - INTERVAL '30 days') AS ai_messages_last_30_days,
+                MAX(messages.sent_at) FILTER (WHERE NOT messages.is_user) AS last_ai_message
+            FROM messages
+            JOIN chats ON chats.id = messages.chat_id
+            JOIN workspaces ON workspaces.id = chats.workspace_id
+            WHERE workspaces.user_id = users.id
+        ) AS usage
+        WHERE users.id = $1"#,
+        id
+    )
+    .fetch_optional(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::ItemNotFound)?;
+    Ok(Json(usage))
+}
+
+pub async fn stats(
+    State(db): State<Pool<Postgres>>,
+    Extension(admin): Admin
+) -> Result<Json<Stats>, ApiError> {
+    log(HTTP, &format!("Admin <{}> requested FETCH stats", admin.user_id));
+    let stats = sqlx::query_as!(
+        Stats,
+        r#"SELECT
+            (SELECT COUNT(*) FROM users) AS "users!",
+            (SELECT COUNT(*) FROM users WHERE role = 'student') AS "students!",
+            (SELECT COUNT(*) FROM users WHERE role = 'teacher') AS "teachers!",
+            (SELECT COUNT(*) FROM users WHERE role = 'admin') AS "admins!",
+            (SELECT COUNT(*) FROM users WHERE disabled_at IS NOT NULL) AS "disabled_users!",
+            (SELECT COUNT(*) FROM workspaces) AS "workspaces!",
+            (SELECT COUNT(*) FROM messages WHERE NOT is_user) AS "ai_messages!",
+            (SELECT COUNT(*) FROM messages WHERE NOT is_user AND sent_at > now() - INTERVAL '30 days') AS "ai_messages_last_30_days!""#
+    )
+    .fetch_one(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(Json(stats))
+}
+
+pub async fn audit_events(
+    State(db): State<Pool<Postgres>>,
+    Extension(admin): Admin,
+    Query(query): Query<AuditQuery>
+) -> Result<Json<Vec<AuditRecord>>, ApiError> {
+    log(HTTP, &format!("Admin <{}> requested FETCH audit events", admin.user_id));
+    Ok(Json(audit::fetch_events(query.user_id, &query, &db).await?))
+}
+
+// Takes effect on the user's next request, the role in tokens already issued is only informational
+pub async fn set_role(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    Extension(admin): Admin,
+    Path(id): Path<i32>,
+    Json(payload): Json<SetRoleRequest>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("Admin <{}> requested SET role of UserID <{}> to <{}>", admin.user_id, id, payload.role.as_str()));
+    // Otherwise the last admin could lock everyone out of this API
+    if id == admin.user_id {
+        return Err(ApiError::CannotModifySelf);
+    }
+    let result = sqlx::query!("UPDATE users SET role = $2 WHERE id = $1", id, payload.role.as_str())
+        .execute(&db)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::ItemNotFound);
+    }
+    let detail = json!({ "admin_id": admin.user_id, "role": payload.role });
+    audit::record(&db, &client, AuditEvent::RoleChanged, Some(id), detail).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Logs the user out everywhere and refuses new logins and personal access tokens until enabled again
+pub async fn disable_user(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    Extension(admin): Admin,
+    Path(id): Path<i32>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("Admin <{}> requested DISABLE UserID <{}>", admin.user_id, id));
+    if id == admin.user_id {
+        return Err(ApiError::CannotModifySelf);
+    }
+    let mut tx = db.begin().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    let result = sqlx::query!(
+        "UPDATE users SET disabled_at = COALESCE(disabled_at, now()) WHERE id = $1",
+        id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::ItemNotFound);
+    }
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL",
+        id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    tx.commit().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    audit::record(&db, &client, AuditEvent::AccountDisabled, Some(id), json!({ "admin_id": admin.user_id })).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn enable_user(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    Extension(admin): Admin,
+    Path(id): Path<i32>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("Admin <{}> requested ENABLE UserID <{}>", admin.user_id, id));
+    let result = sqlx::query!("UPDATE users SET disabled_at = NULL WHERE id = $1", id)
+        .execute(&db)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::ItemNotFound);
+    }
+    audit::record(&db, &client, AuditEvent::AccountEnabled, Some(id), json!({ "admin_id": admin.user_id })).await;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,24 +1,39 @@
-use std::env;
-
 use axum::{http::StatusCode, response::{IntoResponse, Response}};
-use axum_extra::headers::{authorization::Bearer, Authorization};
-use dotenvy::dotenv;
-use jsonwebtoken::{decode, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use crate::auth::auth::ACCESS_AUDIENCE;
+use crate::auth::keys;
+use crate::auth::personal_token::{fetch_personal_token, is_personal_token};
+use crate::auth::role::Role;
+use crate::auth::session::fetch_session_user;
+use super::access::{allowed_unverified, Access};
 
 pub enum ApiError {
     DatabaseOperationFailed,
+    MissingToken,
     InvalidToken,
     TokenExpired,
+    TokenRevoked,
+    EmailNotVerified,
+    InsufficientScope,
+    AccountDisabled,
+    InsufficientRole,
+    CannotModifySelf,
+    InvalidCursor,
     UnauthorizedAccess,
-    ItemNotFound
+    AlreadyMember,
+    LastOwner,
+    ItemNotFound,
+    InternalError
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TokenData {
     pub user_id: i32,
-    pub exp: usize
+    pub session_id: Option<i32>, // None for personal access tokens
+    pub exp: Option<usize>,      // None for personal access tokens that never expire
+    pub role: Role               // Read from the database, not the token, so demotions apply at once
 }
 
 impl IntoResponse for ApiError {
@@ -27,88 +42,130 @@ impl IntoResponse for ApiError {
             ApiError::DatabaseOperationFailed => {
                 (StatusCode::INTERNAL_SERVER_ERROR, "DatabaseOperationFailed").into_response()
             },
+            ApiError::MissingToken => {
+                (StatusCode::UNAUTHORIZED, "MissingToken").into_response()
+            },
             ApiError::InvalidToken => {
                 (StatusCode::UNAUTHORIZED, "InvalidToken").into_response()
             },
             ApiError::TokenExpired => {
                 (StatusCode::UNAUTHORIZED, "TokenExpired").into_response()
             },
+            ApiError::TokenRevoked => {
+                (StatusCode::UNAUTHORIZED, "TokenRevoked").into_response()
+            },
+            ApiError::EmailNotVerified => {
+                (StatusCode::FORBIDDEN, "EmailNotVerified").into_response()
+            },
+            ApiError::InsufficientScope => {
+                (StatusCode::FORBIDDEN, "InsufficientScope").into_response()
+            },
+            ApiError::AccountDisabled => {
+                (StatusCode::FORBIDDEN, "AccountDisabled").into_response()
+            },
+            ApiError::InsufficientRole => {
+                (StatusCode::FORBIDDEN, "InsufficientRole").into_response()
+            },
+            ApiError::CannotModifySelf => {
+                (StatusCode::CONFLICT, "CannotModifySelf").into_response()
+            },
+            ApiError::InvalidCursor => {
+                (StatusCode::BAD_REQUEST, "InvalidCursor").into_response()
+            },
             ApiError::UnauthorizedAccess => {
                 (StatusCode::UNAUTHORIZED, "UnauthorizedAccess").into_response()
             },
+            ApiError::AlreadyMember => {
+                (StatusCode::CONFLICT, "AlreadyMember").into_response()
+            },
+            ApiError::LastOwner => {
+                (StatusCode::CONFLICT, "LastOwner").into_response()
+            },
             ApiError::ItemNotFound => {
                 (StatusCode::NOT_FOUND, "NotFound").into_response()
+            },
+            ApiError::InternalError => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "InternalError").into_response()
             }
         }
     }
 }
 
-pub fn extract_token_data(auth: Authorization<Bearer>) -> Result<TokenData, ApiError> {
-    dotenv().expect("Failed to load environment variables!");
-    let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be defined in .env!");
-
-    let mut validation = Validation::default();
-    validation.validate_exp = true;
-
-    let token_data = decode::<Value>(
-        auth.token(),
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &validation,
-    )
-    .map_err(|err| {
-        if err.kind() == &jsonwebtoken::errors::ErrorKind::ExpiredSignature {
-            ApiError::TokenExpired
-        } else {
-            ApiError::InvalidToken
-        }
-    })?;
+// Checks a bearer token (JWT or personal access token) for the given access.
+// Handlers get this through the AuthUser extractor instead of calling it directly.
+pub async fn authenticate(token: &str, db: &Pool<Postgres>, access: Access) -> Result<TokenData, ApiError> {
+    if is_personal_token(token) {
+        return authorize_personal_token(token, db, access).await;
+    }
+    let claims = keys::verify::<Value>(token, ACCESS_AUDIENCE)
+        .map_err(|err| {
+            if err.kind() == &jsonwebtoken::errors::ErrorKind::ExpiredSignature {
+                ApiError::TokenExpired
+            } else {
+                ApiError::InvalidToken
+            }
+        })?;
 
-    let token_data = TokenData {
-        user_id: token_data.claims
-            .get("sub")
-            .and_then(|v| v.as_i64())
-            .ok_or(ApiError::InvalidToken)? as i32,
-        exp: token_data.claims
-            .get("exp")
-            .and_then(|v| v.as_u64())
-            .ok_or(ApiError::InvalidToken)? as usize,
-    };
+    let user_id = claims
+        .get("sub")
+        .and_then(|v| v.as_i64())
+        .ok_or(ApiError::InvalidToken)? as i32;
+    let session_id = claims
+        .get("sid")
+        .and_then(|v| v.as_i64())
+        .ok_or(ApiError::InvalidToken)? as i32;
+    let exp = claims
+        .get("exp")
+        .and_then(|v| v.as_u64())
+        .ok_or(ApiError::InvalidToken)? as usize;
 
-    println!("Token Data: {:?}", token_data);
-    Ok(token_data)
+    let role = authorize_session(session_id, user_id, db, access).await?;
+    Ok(TokenData {
+        user_id,
+        session_id: Some(session_id),
+        exp: Some(exp),
+        role
+    })
 }
 
-pub fn extract_token_data_str(auth: String) -> Result<TokenData, ApiError> {
-    dotenv().expect("Failed to load environment variables!");
-    let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be defined in .env!");
-
-    let mut validation = Validation::default();
-    validation.validate_exp = true;
-
-    let token_data = decode::<Value>(
-        auth.as_str(),
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &validation,
-    )
-    .map_err(|err| {
-        if err.kind() == &jsonwebtoken::errors::ErrorKind::ExpiredSignature {
-            ApiError::TokenExpired
-        } else {
-            ApiError::InvalidToken
-        }
-    })?;
-
-    let token_data = TokenData {
-        user_id: token_data.claims
-            .get("sub")
-            .and_then(|v| v.as_i64())
-            .ok_or(ApiError::InvalidToken)? as i32,
-        exp: token_data.claims
-            .get("exp")
-            .and_then(|v| v.as_u64())
-            .ok_or(ApiError::InvalidToken)? as usize,
-    };
+// Access tokens stop working as soon as their session is revoked (or the account disabled),
+// and unverified users are held to the UNVERIFIED_RESTRICTIONS policy
+async fn authorize_session(session_id: i32, user_id: i32, db: &Pool<Postgres>, access: Access) -> Result<Role, ApiError> {
+    let user = fetch_session_user(session_id, user_id, db)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?
+        .ok_or(ApiError::TokenRevoked)?;
+    if !user.email_verified && !allowed_unverified(access) {
+        return Err(ApiError::EmailNotVerified);
+    }
+    Ok(Role::from_db(&user.role))
+}
 
-    println!("Token Data: {:?}", token_data);
-    Ok(token_data)
-}
\ No newline at end of file
+// Personal access tokens only reach the routes their scopes name, and never account management
+async fn authorize_personal_token(token: &str, db: &Pool<Postgres>, access: Access) -> Result<TokenData, ApiError> {
+    let personal_token = fetch_personal_token(token, db)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?
+        .ok_or(ApiError::InvalidToken)?;
+    if personal_token.disabled {
+        return Err(ApiError::AccountDisabled);
+    }
+    if personal_token.revoked {
+        return Err(ApiError::TokenRevoked);
+    }
+    if personal_token.expired {
+        return Err(ApiError::TokenExpired);
+    }
+    if access == Access::Account || !personal_token.scopes.iter().any(|scope| scope == access.as_str()) {
+        return Err(ApiError::InsufficientScope);
+    }
+    if !personal_token.email_verified && !allowed_unverified(access) {
+        return Err(ApiError::EmailNotVerified);
+    }
+    Ok(TokenData {
+        user_id: personal_token.user_id,
+        session_id: None,
+        exp: personal_token.exp.map(|exp| exp as usize),
+        role: Role::from_db(&personal_token.role)
+    })
+}
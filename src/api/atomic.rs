@@ -21,7 +21,7 @@ impl IntoResponse for NodeOperationError {
                 (StatusCode::CONFLICT, "RootAlreadyExists").into_response()
             },
             NodeOperationError::ForbiddenLink => {
-                (StatusCode::FORBIDDEN, "ForbiddenLink").into_response()
+                (StatusCode::BAD_REQUEST, "ForbiddenLink").into_response()
             },
             NodeOperationError::CyclicReference => {
                 (StatusCode::BAD_REQUEST, "CyclicReference").into_response()
@@ -0,0 +1,139 @@
+use axum::{extract::{Query, State}, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use crate::debug::{errlog, log, LogType::HTTP};
+use super::access::scope;
+use super::api::ApiError;
+use super::auth_user::AuthUser;
+use super::client::ClientInfo;
+
+/*
+
+    AUDIT LOG
+
+    Unlike debug::log, these events are kept in audit_events so they can be looked up later:
+    by the user they are about (/auth/audit) and by admins (/admin/audit). Each one records
+    the IP and user agent of the request that caused it. Recording is best effort, a failing
+    insert is logged but never fails the request that triggered it.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    LoginSucceeded,
+    LoginFailed,
+    Registered,
+    PasswordChanged,
+    PasswordReset,
+    SessionRevoked,
+    TokenCreated,
+    TokenRevoked,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    AccountDeleted,
+    AccountDisabled,
+    AccountEnabled,
+    RoleChanged,
+    WorkspaceDeleted,
+    WorkspaceRestored,
+    WorkspacePurged,
+    NodeDeleted,
+    ShareCreated,
+    ShareRevoked
+}
+
+impl AuditEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEvent::LoginSucceeded => "login.succeeded",
+            AuditEvent::LoginFailed => "login.failed",
+            AuditEvent::Registered => "account.registered",
+            AuditEvent::PasswordChanged => "password.changed",
+            AuditEvent::PasswordReset => "password.reset",
+            AuditEvent::SessionRevoked => "session.revoked",
+            AuditEvent::TokenCreated => "token.created",
+            AuditEvent::TokenRevoked => "token.revoked",
+            AuditEvent::TwoFactorEnabled => "2fa.enabled",
+            AuditEvent::TwoFactorDisabled => "2fa.disabled",
+            AuditEvent::AccountDeleted => "account.deleted",
+            AuditEvent::AccountDisabled => "account.disabled",
+            AuditEvent::AccountEnabled => "account.enabled",
+            AuditEvent::RoleChanged => "account.role_changed",
+            AuditEvent::WorkspaceDeleted => "workspace.deleted",
+            AuditEvent::WorkspaceRestored => "workspace.restored",
+            AuditEvent::WorkspacePurged => "workspace.purged",
+            AuditEvent::NodeDeleted => "node.deleted",
+            AuditEvent::ShareCreated => "share.created",
+            AuditEvent::ShareRevoked => "share.revoked"
+        }
+    }
+}
+
+// user_id is the account the event is about, None when there is no such account (a login with an unknown name)
+pub async fn record(db: &Pool<Postgres>, client: &ClientInfo, event: AuditEvent, user_id: Option<i32>, detail: Value) {
+    let result = sqlx::query!(
+        "INSERT INTO audit_events (user_id, event, detail, ip, user_agent) VALUES ($1, $2, $3, $4, $5)",
+        user_id,
+        event.as_str(),
+        detail,
+        client.ip.to_string(),
+        client.user_agent
+    )
+    .execute(db)
+    .await;
+    if let Err(e) = result {
+        errlog(HTTP, &e);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    // Admins only, ignored on /auth/audit
+    pub user_id: Option<i32>,
+    pub event: Option<String>,
+    // Id of the last event of the previous page
+    pub before: Option<i64>,
+    pub limit: Option<i64>
+}
+
+#[derive(Serialize)]
+pub struct AuditRecord {
+    id: i64,
+    user_id: Option<i32>,
+    event: String,
+    detail: Value,
+    ip: String,
+    user_agent: Option<String>,
+    created: DateTime<Utc>
+}
+
+// Newest first, pages through with ?before={id}
+pub async fn fetch_events(user_id: Option<i32>, query: &AuditQuery, db: &Pool<Postgres>) -> Result<Vec<AuditRecord>, ApiError> {
+    sqlx::query_as!(
+        AuditRecord,
+        "SELECT id, user_id, event, detail, ip, user_agent, created FROM audit_events
+        WHERE ($1::INTEGER IS NULL OR user_id = $1)
+            AND ($2::TEXT IS NULL OR event = $2)
+            AND ($3::BIGINT IS NULL OR id < $3)
+        ORDER BY id DESC
+        LIMIT $4",
+        user_id,
+        query.event.as_deref(),
+        query.before,
+        query.limit.unwrap_or(50).clamp(1, 200)
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)
+}
+
+pub async fn my_events(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>,
+    Query(query): Query<AuditQuery>
+) -> Result<Json<Vec<AuditRecord>>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested FETCH audit events", user.user_id));
+    Ok(Json(fetch_events(Some(user.user_id), &query, &db).await?))
+}
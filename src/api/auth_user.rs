@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use axum::{
+    extract::{FromRef, FromRequestParts, Query, Request, State},
+    http::{header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use axum_extra::headers::{authorization::Bearer, Authorization, HeaderMapExt};
+use sqlx::{Pool, Postgres};
+use super::access::{scope, Scope};
+use crate::auth::role::Role;
+use super::api::{authenticate, ApiError};
+
+// The user behind a request's bearer token, already checked for the access S stands for.
+// Rejects with the same ApiError codes on every route.
+pub struct AuthUser<S: Scope> {
+    pub user_id: i32,
+    pub session_id: Option<i32>, // None for personal access tokens
+    pub role: Role,
+    scope: PhantomData<S>
+}
+
+// Written out because a derive would also require S: Clone
+impl<S: Scope> Clone for AuthUser<S> {
+    fn clone(&self) -> Self {
+        AuthUser {
+            user_id: self.user_id,
+            session_id: self.session_id,
+            role: self.role,
+            scope: PhantomData
+        }
+    }
+}
+
+// Like AuthUser, but None when no token was sent at all. A token that is sent must still be valid.
+pub struct OptionalAuthUser<S: Scope>(pub Option<AuthUser<S>>);
+
+impl<S: Scope> AuthUser<S> {
+    // For tokens that do not arrive in a header, like the first message of a websocket
+    pub async fn from_token(token: &str, db: &Pool<Postgres>) -> Result<AuthUser<S>, ApiError> {
+        let token_data = authenticate(token, db, S::ACCESS).await?;
+        Ok(AuthUser {
+            user_id: token_data.user_id,
+            session_id: token_data.session_id,
+            role: token_data.role,
+            scope: PhantomData
+        })
+    }
+}
+
+// Browsers cannot set headers on a websocket handshake, so upgrades may
+// send the token as ?access_token= instead of the Authorization header
+fn bearer_token(parts: &Parts) -> Result<Option<String>, ApiError> {
+    if parts.headers.contains_key(header::AUTHORIZATION) {
+        let auth = parts.headers
+            .typed_get::<Authorization<Bearer>>()
+            .ok_or(ApiError::InvalidToken)?;
+        return Ok(Some(auth.token().into()));
+    }
+    let upgrade = parts.headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    if upgrade {
+        let Query(mut query) = Query::<HashMap<String, String>>::try_from_uri(&parts.uri)
+            .map_err(|_| ApiError::InvalidToken)?;
+        return Ok(query.remove("access_token"));
+    }
+    Ok(None)
+}
+
+impl<S, T> FromRequestParts<T> for AuthUser<S>
+where
+    S: Scope,
+    T: Send + Sync,
+    Pool<Postgres>: FromRef<T>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &T) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(parts)?.ok_or(ApiError::MissingToken)?;
+        AuthUser::from_token(&token, &Pool::from_ref(state)).await
+    }
+}
+
+impl<S, T> FromRequestParts<T> for OptionalAuthUser<S>
+where
+    S: Scope,
+    T: Send + Sync,
+    Pool<Postgres>: FromRef<T>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &T) -> Result<Self, Self::Rejection> {
+        match bearer_token(parts)? {
+            Some(token) => Ok(OptionalAuthUser(Some(AuthUser::from_token(&token, &Pool::from_ref(state)).await?))),
+            None => Ok(OptionalAuthUser(None))
+        }
+    }
+}
+
+// State of the require_role layer: the lowest role let through
+#[derive(Clone)]
+pub struct RoleGuard {
+    db: Pool<Postgres>,
+    role: Role
+}
+
+impl RoleGuard {
+    pub fn new(db: Pool<Postgres>, role: Role) -> RoleGuard {
+        RoleGuard { db, role }
+    }
+}
+
+impl FromRef<RoleGuard> for Pool<Postgres> {
+    fn from_ref(guard: &RoleGuard) -> Pool<Postgres> {
+        guard.db.clone()
+    }
+}
+
+// Route layer for whole routers. Personal access tokens never pass (they cannot act for the account),
+// and the checked user is left in the request extensions as an AuthUser<scope::Account>.
+pub async fn require_role(
+    State(guard): State<RoleGuard>,
+    user: AuthUser<scope::Account>,
+    mut request: Request,
+    next: Next
+) -> Result<Response, ApiError> {
+    if user.role < guard.role {
+        return Err(ApiError::InsufficientRole);
+    }
+    request.extensions_mut().insert(user);
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,72 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::LazyLock;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts},
+};
+use dotenvy::dotenv;
+use crate::config::env_or;
+
+// Only trust X-Forwarded-For / X-Real-IP when running behind our own reverse proxy,
+// otherwise any client could pick the address it is rate limited and audited under
+struct ProxyConfig {
+    trust_headers: bool,
+    // Proxies of ours in front of the server, each appends the address it got the request from
+    hops: usize
+}
+
+static PROXY: LazyLock<ProxyConfig> = LazyLock::new(|| {
+    dotenv().expect("Failed to load environment variables!");
+    ProxyConfig {
+        trust_headers: env_or("TRUST_PROXY_HEADERS", false),
+        hops: env_or("TRUSTED_PROXY_HOPS", 1usize).max(1)
+    }
+});
+
+pub fn load_proxy_config() {
+    LazyLock::force(&PROXY);
+}
+
+// Entries of X-Forwarded-For left of what our proxies appended are whatever the client sent.
+// The one our outermost proxy appended is the first we can trust, counted from the right.
+fn forwarded_for(value: &str) -> Option<IpAddr> {
+    let entries: Vec<&str> = value.split(',').map(str::trim).collect();
+    let index = entries.len().checked_sub(PROXY.hops)?;
+    entries[index].parse().ok()
+}
+
+// Who is on the other end of a request
+pub struct ClientInfo {
+    pub ip: IpAddr,
+    pub user_agent: Option<String>
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let forwarded = if PROXY.trust_headers {
+            match parts.headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+                Some(value) => forwarded_for(value),
+                None => parts.headers
+                    .get("x-real-ip")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse().ok())
+            }
+        } else {
+            None
+        };
+        let peer = parts.extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let user_agent = parts.headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        Ok(ClientInfo {
+            ip: forwarded.or(peer).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            user_agent
+        })
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::{HashMap, HashSet};
+use axum::{
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::debug::{log, LogType::HTTP};
+use super::access::scope;
+use super::api::ApiError;
+use super::auth_user::AuthUser;
+use super::member::{require_workspace_role, WorkspaceRole};
+use super::tree::{Tree, TreeNode};
+
+/*
+
+    EXPORT
+
+    JSON        -- Lossless bundle of the workspace and its tree, the format import reads back.
+    MARKDOWN    -- Indented checklist, [x] for resolved nodes.
+    OPML        -- Outline for outliner apps, node details kept as attributes.
+    MERMAID     -- Flowchart.
+    DOT         -- Graphviz digraph.
+
+    The tree is a DAG, so outlines would repeat a node under every parent it has (and loop
+    forever on a cycle). Outlines write the branches of a node once, at its first place,
+    and only a reference to it everywhere else. The graph formats have no such problem.
+
+*/
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Markdown,
+    Opml,
+    Mermaid,
+    Dot
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: Option<ExportFormat>
+}
+
+pub const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    pub title: String,
+    pub description: Option<String>,
+    pub tree: Tree
+}
+
+// Marks the place of a node whose branches were already written further up. Names need not be
+// unique, so Markdown points the mark at an anchor left where the node was written first.
+fn see_above(id: i32) -> String {
+    format!(" (see above #n{})", id)
+}
+
+fn anchor(id: i32) -> String {
+    format!(" <a id=\"n{}\"></a>", id)
+}
+
+pub async fn export_workspace(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceRead>,
+    Path(id): Path<i32>,
+    Query(query): Query<ExportQuery>
+) -> Result<Response, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested EXPORT workspace <{}>", user.user_id, id));
+    require_workspace_role(id, user.user_id, WorkspaceRole::Viewer, &db).await?;
+    let mut conn = db.acquire().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    let workspace = sqlx::query!("SELECT title, description FROM workspaces WHERE id = $1", id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    let bundle = Bundle {
+        version: BUNDLE_VERSION,
+        title: workspace.title,
+        description: workspace.description,
+        tree: Tree::load(id, &mut conn).await?
+    };
+
+    let (content_type, body) = match query.format.unwrap_or(ExportFormat::Json) {
+        ExportFormat::Json => (
+            "application/json",
+            serde_json::to_string_pretty(&bundle).map_err(|_| ApiError::DatabaseOperationFailed)?
+        ),
+        ExportFormat::Markdown => ("text/markdown; charset=utf-8", markdown(&bundle)),
+        ExportFormat::Opml => ("text/x-opml; charset=utf-8", opml(&bundle)),
+        ExportFormat::Mermaid => ("text/plain; charset=utf-8", mermaid(&bundle)),
+        ExportFormat::Dot => ("text/vnd.graphviz; charset=utf-8", dot(&bundle))
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+enum Step<'a> {
+    // first is false where the node was already written further up, branches are then skipped
+    Node { node: &'a TreeNode, depth: usize, first: bool, has_branches: bool },
+    // After the last branch of a node that has any
+    Close { depth: usize }
+}
+
+// Walks the tree depth first from its roots, expanding every node once
+struct Outline<'a> {
+    nodes: HashMap<i32, &'a TreeNode>,
+    branches: HashMap<i32, Vec<i32>>,
+    roots: Vec<i32>
+}
+
+impl<'a> Outline<'a> {
+    fn new(tree: &'a Tree) -> Outline<'a> {
+        let nodes: HashMap<i32, &TreeNode> = tree.nodes.iter().map(|node| (node.id, node)).collect();
+        let mut branches: HashMap<i32, Vec<i32>> = HashMap::new();
+        let mut has_parent: HashSet<i32> = HashSet::new();
+        for (node, parent) in &tree.edges {
+            if nodes.contains_key(node) && nodes.contains_key(parent) {
+                branches.entry(*parent).or_default().push(*node);
+                has_parent.insert(*node);
+            }
+        }
+        branches.values_mut().for_each(|children| children.sort());
+
+        // The workspace root first, then any other parentless node. Nodes only reachable
+        // through a cycle are added last so nothing is left out.
+        let mut ids: Vec<i32> = tree.nodes.iter().map(|node| node.id).collect();
+        ids.sort();
+        let mut roots: Vec<i32> = tree.root_id.filter(|root_id| nodes.contains_key(root_id)).into_iter().collect();
+        roots.extend(ids.iter().filter(|id| !has_parent.contains(id) && Some(**id) != tree.root_id));
+        let mut outline = Outline { nodes, branches, roots };
+        let mut reached: HashSet<i32> = HashSet::new();
+        outline.walk(&mut |step| {
+            if let Step::Node { node, .. } = step {
+                reached.insert(node.id);
+            }
+        });
+        outline.roots.extend(ids.into_iter().filter(|id| !reached.contains(id)));
+        outline
+    }
+
+    fn walk(&self, visit: &mut impl FnMut(Step)) {
+        let mut expanded: HashSet<i32> = HashSet::new();
+        for root in &self.roots {
+            self.walk_node(*root, 0, &mut expanded, visit);
+        }
+    }
+
+    fn walk_node(&self, id: i32, depth: usize, expanded: &mut HashSet<i32>, visit: &mut impl FnMut(Step)) {
+        let Some(node) = self.nodes.get(&id) else {
+            return;
+        };
+        let first = expanded.insert(id);
+        let branches = self.branches.get(&id).filter(|branches| first && !branches.is_empty());
+        visit(Step::Node { node, depth, first, has_branches: branches.is_some() });
+        if let Some(branches) = branches {
+            for branch in branches {
+                self.walk_node(*branch, depth + 1, expanded, visit);
+            }
+            visit(Step::Close { depth });
+        }
+    }
+}
+
+pub(super) fn markdown(bundle: &Bundle) -> String {
+    let mut out = format!("# {}\n\n", single_line(&bundle.title));
+    if let Some(description) = bundle.description.as_deref().filter(|d| !d.trim().is_empty()) {
+        for line in description.trim().lines() {
+            out.push_str(&format!("{}\n", escape_line(line.trim())));
+        }
+        out.push('\n');
+    }
+    let outline = Outline::new(&bundle.tree);
+    let mut referenced: HashSet<i32> = HashSet::new();
+    outline.walk(&mut |step| {
+        if let Step::Node { node, first: false, .. } = step {
+            referenced.insert(node.id);
+        }
+    });
+    outline.walk(&mut |step| {
+        let Step::Node { node, depth, first, .. } = step else {
+            return;
+        };
+        let indent = "  ".repeat(depth);
+        out.push_str(&format!("{}- [{}] ", indent, if node.resolved { "x" } else { " " }));
+        if let Some(icon) = node.icon.as_deref().filter(|icon| !icon.trim().is_empty()) {
+            out.push_str(&format!("{} ", icon.trim()));
+        }
+        out.push_str(&escape_name(&node.name));
+        if node.optional {
+            out.push_str(" *(optional)*");
+        }
+        if !first {
+            out.push_str(&see_above(node.id));
+        } else if referenced.contains(&node.id) {
+            out.push_str(&anchor(node.id));
+        }
+        out.push('\n');
+        if first {
+            for line in node.summary.as_deref().unwrap_or_default().lines().filter(|line| !line.trim().is_empty()) {
+                out.push_str(&format!("{}  {}\n", indent, escape_line(line.trim())));
+            }
+        }
+    });
+    out
+}
+
+pub(super) fn opml(bundle: &Bundle) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n");
+    out.push_str(&format!("  <head>\n    <title>{}</title>\n  </head>\n  <body>\n", escape_xml(&bundle.title)));
+    Outline::new(&bundle.tree).walk(&mut |step| match step {
+        Step::Node { node, depth, first, has_branches } => {
+            out.push_str(&format!("{}<outline text=\"{}\"", "  ".repeat(depth + 2), escape_xml(&node.name)));
+            if first {
+                out.push_str(&format!(" id=\"{}\"", node.id));
+                if let Some(summary) = node.summary.as_deref().filter(|summary| !summary.is_empty()) {
+                    out.push_str(&format!(" _note=\"{}\"", escape_xml(summary)));
+                }
+                if let Some(icon) = node.icon.as_deref().filter(|icon| !icon.is_empty()) {
+                    out.push_str(&format!(" icon=\"{}\"", escape_xml(icon)));
+                }
+                out.push_str(&format!(" resolved=\"{}\" optional=\"{}\"", node.resolved, node.optional));
+            } else {
+                out.push_str(&format!(" ref=\"{}\"", node.id));
+            }
+            out.push_str(if has_branches { ">\n" } else { "/>\n" });
+        },
+        Step::Close { depth } => out.push_str(&format!("{}</outline>\n", "  ".repeat(depth + 2)))
+    });
+    out.push_str("  </body>\n</opml>\n");
+    out
+}
+
+fn mermaid(bundle: &Bundle) -> String {
+    let mut out = format!("---\ntitle: {}\n---\nflowchart TD\n", yaml_string(&single_line(&bundle.title)));
+    for node in sorted_nodes(&bundle.tree) {
+        let label = match node.icon.as_deref().filter(|icon| !icon.trim().is_empty()) {
+            Some(icon) => format!("{} {}", icon.trim(), single_line(&node.name)),
+            None => single_line(&node.name)
+        };
+        out.push_str(&format!("    n{}[\"{}\"]", node.id, label.replace('"', "#quot;")));
+        match (node.resolved, node.optional) {
+            (true, _) => out.push_str(":::resolved"),
+            (false, true) => out.push_str(":::optional"),
+            _ => {}
+        }
+        out.push('\n');
+    }
+    for (node, parent) in sorted_edges(&bundle.tree) {
+        out.push_str(&format!("    n{} --> n{}\n", parent, node));
+    }
+    out.push_str("    classDef resolved fill:#c8e6c9,stroke:#2e7d32\n");
+    out.push_str("    classDef optional stroke-dasharray: 5 5\n");
+    out
+}
+
+fn dot(bundle: &Bundle) -> String {
+    let mut out = format!(
+        "digraph workspace {{\n    label=\"{}\";\n    labelloc=t;\n    node [shape=box, style=rounded];\n",
+        escape_dot(&bundle.title)
+    );
+    for node in sorted_nodes(&bundle.tree) {
+        let label = match node.icon.as_deref().filter(|icon| !icon.trim().is_empty()) {
+            Some(icon) => format!("{} {}", icon.trim(), node.name),
+            None => node.name.clone()
+        };
+        let mut style = vec!["rounded"];
+        if node.resolved {
+            style.push("filled");
+        }
+        if node.optional {
+            style.push("dashed");
+        }
+        out.push_str(&format!("    n{} [label=\"{}\", style=\"{}\"", node.id, escape_dot(&label), style.join(",")));
+        if node.resolved {
+            out.push_str(", fillcolor=palegreen");
+        }
+        out.push_str("];\n");
+    }
+    for (node, parent) in sorted_edges(&bundle.tree) {
+        out.push_str(&format!("    n{} -> n{};\n", parent, node));
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn sorted_nodes(tree: &Tree) -> Vec<&TreeNode> {
+    let mut nodes: Vec<&TreeNode> = tree.nodes.iter().collect();
+    nodes.sort_by_key(|node| node.id);
+    nodes
+}
+
+// Edges between nodes of the tree only, (node, parent) pairs
+fn sorted_edges(tree: &Tree) -> Vec<(i32, i32)> {
+    let ids: HashSet<i32> = tree.nodes.iter().map(|node| node.id).collect();
+    let mut edges: Vec<(i32, i32)> = tree.edges
+        .iter()
+        .filter(|(node, parent)| ids.contains(node) && ids.contains(parent))
+        .copied()
+        .collect();
+    edges.sort_by_key(|(node, parent)| (*parent, *node));
+    edges
+}
+
+fn single_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// Text lines of Markdown that would read as a list item or a heading ("- ", "1. ", "#") get
+// their marker escaped, so they stay text. Import takes the backslash off again.
+fn escape_line(line: &str) -> String {
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let bullet = ["-", "*", "+"].iter().any(|marker| line == *marker || line.starts_with(&format!("{} ", marker)));
+    if bullet || line.starts_with('#') || line.starts_with('\\') {
+        format!("\\{}", line)
+    } else if digits > 0 && line[digits..].starts_with(". ") {
+        format!("{}\\{}", &line[..digits], &line[digits..])
+    } else {
+        line.into()
+    }
+}
+
+// Names of list items that import would read as markup get a backslash: before a first word
+// that looks like an icon, and before the last character of an ending like " *(optional)*",
+// " (see above)" or an anchor. A backslash already in those places is escaped too.
+fn escape_name(name: &str) -> String {
+    let name = single_line(name);
+    let icon = name.split_once(' ').is_some_and(|(first, _)| first.chars().all(|c| !c.is_ascii() && !c.is_alphanumeric()));
+    let mut out = if icon || name.starts_with('\\') { format!("\\{}", name) } else { name.clone() };
+    let markup = ["(optional)", "(optional)*", "(optional)_", "</a>"].iter().any(|end| name.ends_with(end))
+        || (name.ends_with(')') && name.contains("(see above"));
+    if let Some(last) = name.chars().last().filter(|c| [')', '*', '_', '>'].contains(c)) {
+        if markup || name[..name.len() - last.len_utf8()].ends_with('\\') {
+            out.insert(out.len() - last.len_utf8(), '\\');
+        }
+    }
+    out
+}
+
+// A double quoted YAML scalar, so titles like "Goal: #1" or "- todo" stay plain text
+fn yaml_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\n', "&#10;")
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,247 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::auth::role::Role;
+use crate::debug::{log, LogType::HTTP};
+use super::access::scope;
+use super::api::ApiError;
+use super::auth_user::AuthUser;
+use super::validation::{Validate, ValidJson, ValidationErrors, Validator};
+
+/*
+
+    GROUPS
+
+    Named sets of users, a class for example, that templates can be shared with. Teachers
+    and admins create them and become their owner, the owner adds and removes members.
+    Members can see who else is in the group and leave it. The owner cannot leave, they
+    delete the group instead.
+
+*/
+
+#[derive(Deserialize)]
+pub struct CreateGroupRequest {
+    name: String
+}
+
+impl Validate for CreateGroupRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .required("name", &self.name)
+            .max_length("name", &self.name, 100)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AddGroupMemberRequest {
+    // Username or email of the user to add
+    identifier: String
+}
+
+#[derive(Serialize)]
+pub struct Group {
+    id: i32,
+    name: String,
+    owner: String,
+    members: i64,
+    created: DateTime<Utc>
+}
+
+#[derive(Serialize)]
+pub struct GroupMember {
+    user_id: i32,
+    username: String,
+    created: DateTime<Utc>
+}
+
+// Returns the owner of the group, strangers are refused
+async fn require_group_member(group_id: i32, user_id: i32, db: &Pool<Postgres>) -> Result<i32, ApiError> {
+    sqlx::query_scalar!(
+        "SELECT user_groups.owner_id FROM user_groups
+        JOIN user_group_members ON user_group_members.group_id = user_groups.id
+        WHERE user_groups.id = $1 AND user_group_members.user_id = $2",
+        group_id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::UnauthorizedAccess)
+}
+
+async fn require_group_owner(group_id: i32, user_id: i32, db: &Pool<Postgres>) -> Result<(), ApiError> {
+    if require_group_member(group_id, user_id, db).await? != user_id {
+        return Err(ApiError::InsufficientRole);
+    }
+    Ok(())
+}
+
+pub async fn is_group_member(group_id: i32, user_id: i32, db: &Pool<Postgres>) -> Result<bool, ApiError> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM user_group_members WHERE group_id = $1 AND user_id = $2) AS "exists!""#,
+        group_id,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)
+}
+
+pub async fn create(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>,
+    ValidJson(payload): ValidJson<CreateGroupRequest>
+) -> Result<(StatusCode, Json<Group>), ApiError> {
+    log(HTTP, &format!("UserID <{}> requested CREATE group <{}>", user.user_id, payload.name));
+    if user.role < Role::Teacher {
+        return Err(ApiError::InsufficientRole);
+    }
+    let group = sqlx::query_as!(
+        Group,
+        r#"WITH user_group AS (
+            INSERT INTO user_groups (name, owner_id) VALUES ($1, $2) RETURNING id, name, created
+        ), member AS (
+            INSERT INTO user_group_members (group_id, user_id) SELECT id, $2 FROM user_group
+        )
+        SELECT user_group.id AS "id!", user_group.name AS "name!", users.username AS owner,
+            1::BIGINT AS "members!", user_group.created AS "created!"
+        FROM user_group, users WHERE users.id = $2"#,
+        payload.name.trim(),
+        user.user_id
+    )
+    .fetch_one(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok((StatusCode::CREATED, Json(group)))
+}
+
+// The groups the user owns or belongs to
+pub async fn list(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>
+) -> Result<Json<Vec<Group>>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested FETCH groups", user.user_id));
+    let groups = sqlx::query_as!(
+        Group,
+        r#"SELECT user_groups.id, user_groups.name, users.username AS owner,
+            (SELECT COUNT(*) FROM user_group_members WHERE group_id = user_groups.id) AS "members!",
+            user_groups.created
+        FROM user_groups
+        JOIN users ON users.id = user_groups.owner_id
+        JOIN user_group_members ON user_group_members.group_id = user_groups.id
+        WHERE user_group_members.user_id = $1
+        ORDER BY user_groups.name, user_groups.id"#,
+        user.user_id
+    )
+    .fetch_all(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(Json(groups))
+}
+
+pub async fn members(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>,
+    Path(id): Path<i32>
+) -> Result<Json<Vec<GroupMember>>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested FETCH members of group <{}>", user.user_id, id));
+    require_group_member(id, user.user_id, &db).await?;
+    let members = sqlx::query_as!(
+        GroupMember,
+        "SELECT user_group_members.user_id, users.username, user_group_members.created
+        FROM user_group_members
+        JOIN users ON users.id = user_group_members.user_id
+        WHERE user_group_members.group_id = $1
+        ORDER BY users.username",
+        id
+    )
+    .fetch_all(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(Json(members))
+}
+
+pub async fn add_member(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>,
+    Path(id): Path<i32>,
+    Json(payload): Json<AddGroupMemberRequest>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested ADD <{}> to group <{}>", user.user_id, payload.identifier, id));
+    require_group_owner(id, user.user_id, &db).await?;
+    let member_id = sqlx::query_scalar!(
+        "SELECT id FROM users WHERE lower(username) = lower($1) OR lower(email) = lower($1)",
+        payload.identifier.trim()
+    )
+    .fetch_optional(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::ItemNotFound)?;
+    let result = sqlx::query!(
+        "INSERT INTO user_group_members (group_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        id,
+        member_id
+    )
+    .execute(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::AlreadyMember);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// The owner removes anyone but themselves, members only themselves
+pub async fn remove_member(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>,
+    Path((id, member_id)): Path<(i32, i32)>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested REMOVE member <{}> from group <{}>", user.user_id, member_id, id));
+    let owner_id = require_group_member(id, user.user_id, &db).await?;
+    if member_id == owner_id {
+        return Err(ApiError::CannotModifySelf);
+    }
+    if member_id != user.user_id && owner_id != user.user_id {
+        return Err(ApiError::InsufficientRole);
+    }
+    let result = sqlx::query!(
+        "DELETE FROM user_group_members WHERE group_id = $1 AND user_id = $2",
+        id,
+        member_id
+    )
+    .execute(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::ItemNotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Templates shared with the group become private to their owners again
+pub async fn delete(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>,
+    Path(id): Path<i32>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested DELETE group <{}>", user.user_id, id));
+    require_group_owner(id, user.user_id, &db).await?;
+    let mut tx = db.begin().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    sqlx::query!("UPDATE templates SET visibility = 'private', group_id = NULL WHERE group_id = $1", id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    sqlx::query!("DELETE FROM user_groups WHERE id = $1", id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    tx.commit().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,1006 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use crate::debug::{log, LogType::HTTP};
+use super::access::scope;
+use super::api::ApiError;
+use super::auth_user::AuthUser;
+use super::export::{Bundle, BUNDLE_VERSION};
+use super::tree::{insert_workspace, Tree, TreeNode};
+use super::validation::{Validate, ValidJson, ValidationErrors, Validator};
+
+/*
+
+    IMPORT
+
+    Reads what export writes, so a workspace survives the round trip:
+
+    JSON        -- The lossless bundle.
+    MARKDOWN    -- A list, nested by indentation. "- [x]" marks a resolved node, "*(optional)*"
+                   after the name an optional one, an emoji before the name is its icon. Indented
+                   lines below an item are its summary. A "# Title" and a paragraph before the
+                   list become the title and description of the workspace. Summary and
+                   description lines escape a leading list marker or "#" with a backslash.
+                   Names escape a first word that looks like an icon, and the last character
+                   of an ending that would read as a marker or an anchor.
+    OPML        -- Outlines, with text as the name, _note as the summary and icon, resolved
+                   and optional as attributes.
+
+    A node with several parents is written once and referenced everywhere else: in Markdown
+    with "(see above #n3)" pointing at the <a id="n3"></a> after the name where it was written,
+    in OPML with ref="{id}". A bare "(see above)" looks the node up by name, which has to be
+    unique among the items above. Several top-level items are put under
+    a new root named after the workspace. Nothing is created unless the whole input is valid,
+    every problem found is reported with its line.
+
+*/
+
+const MAX_NODES: usize = 2000;
+
+// A reference by name only, as written by hand
+const SEE_ABOVE: &str = " (see above)";
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    Json,
+    Markdown,
+    Opml
+}
+
+#[derive(Deserialize)]
+pub struct ImportRequest {
+    format: ImportFormat,
+    content: String,
+    // Overrides the title found in the content
+    title: Option<String>
+}
+
+impl Validate for ImportRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut validator = Validator::new();
+        validator
+            .required("content", &self.content)
+            .max_length("content", &self.content, 1_000_000);
+        if let Some(title) = &self.title {
+            validator
+                .required("title", title)
+                .max_length("title", title, 100);
+        }
+        validator.finish()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ImportResponse {
+    workspace_id: i32,
+    nodes: usize
+}
+
+#[derive(Serialize)]
+pub struct ImportError {
+    // None when the problem has no single place, like a missing root in a JSON bundle
+    line: Option<usize>,
+    message: String
+}
+
+pub struct ImportErrors(Vec<ImportError>);
+
+impl IntoResponse for ImportErrors {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({
+                "error": "ImportFailed",
+                "errors": self.0
+            }))
+        ).into_response()
+    }
+}
+
+fn error(line: Option<usize>, message: String) -> ImportError {
+    ImportError { line, message }
+}
+
+// A parsed input, with where each node and link came from to point errors at them
+#[derive(Default)]
+struct Parsed {
+    title: Option<String>,
+    title_line: Option<usize>,
+    description: Option<String>,
+    tree: Tree,
+    node_lines: HashMap<i32, usize>,
+    edge_lines: HashMap<(i32, i32), usize>
+}
+
+impl Parsed {
+    fn add_node(&mut self, node: TreeNode, parent: Option<i32>, line: usize) {
+        self.node_lines.insert(node.id, line);
+        if let Some(parent) = parent {
+            self.add_edge(node.id, parent, line);
+        }
+        self.tree.nodes.push(node);
+    }
+
+    fn add_edge(&mut self, node: i32, parent: i32, line: usize) {
+        if let Entry::Vacant(entry) = self.edge_lines.entry((node, parent)) {
+            entry.insert(line);
+            self.tree.edges.push((node, parent));
+        }
+    }
+
+    fn next_id(&self) -> i32 {
+        self.tree.nodes.len() as i32 + 1
+    }
+
+    // Outlines may have several top-level items, a workspace has one root
+    fn set_root(&mut self, top_level: Vec<i32>) {
+        match top_level.as_slice() {
+            [] => {},
+            [root_id] => self.tree.root_id = Some(*root_id),
+            _ => {
+                let root_id = self.next_id();
+                let name = self.title.clone().unwrap_or("Imported workspace".into());
+                self.tree.nodes.push(TreeNode { id: root_id, name, summary: None, optional: false, resolved: false, icon: None });
+                self.tree.edges.extend(top_level.iter().map(|id| (*id, root_id)));
+                self.tree.root_id = Some(root_id);
+            }
+        }
+    }
+}
+
+pub async fn import_workspace(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    ValidJson(payload): ValidJson<ImportRequest>
+) -> Result<(StatusCode, Json<ImportResponse>), Response> {
+    log(HTTP, &format!("UserID <{}> requested IMPORT workspace", user.user_id));
+    let parsed = match payload.format {
+        ImportFormat::Json => parse_json(&payload.content),
+        ImportFormat::Markdown => parse_markdown(&payload.content),
+        ImportFormat::Opml => parse_opml(&payload.content)
+    }
+    .and_then(|parsed| check(parsed, payload.title.is_some()))
+    .map_err(|errors| ImportErrors(errors).into_response())?;
+
+    let title = payload.title.or(parsed.title).unwrap_or("Imported workspace".into());
+    let mut tx = db.begin().await.map_err(|_| ApiError::DatabaseOperationFailed.into_response())?;
+    let workspace_id = insert_workspace(user.user_id, &title, parsed.description.as_deref(), &mut *tx)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    parsed.tree.insert(workspace_id, &mut tx).await.map_err(IntoResponse::into_response)?;
+    tx.commit().await.map_err(|_| ApiError::DatabaseOperationFailed.into_response())?;
+    Ok((StatusCode::CREATED, Json(ImportResponse { workspace_id, nodes: parsed.tree.nodes.len() })))
+}
+
+// The rules every format has to pass: the limits of the node and workspace fields, and a DAG
+fn check(parsed: Parsed, title_given: bool) -> Result<Parsed, Vec<ImportError>> {
+    let mut errors: Vec<ImportError> = vec![];
+    let tree = &parsed.tree;
+    if tree.nodes.is_empty() {
+        return Err(vec![error(None, "contains no nodes".into())]);
+    }
+    if tree.nodes.len() > MAX_NODES {
+        return Err(vec![error(None, format!("contains {} nodes, at most {} can be imported", tree.nodes.len(), MAX_NODES))]);
+    }
+    if !title_given && parsed.title.as_ref().is_some_and(|title| title.chars().count() > 100) {
+        errors.push(error(parsed.title_line, "title must be at most 100 characters".into()));
+    }
+    if parsed.description.as_ref().is_some_and(|description| description.chars().count() > 1000) {
+        errors.push(error(None, "description must be at most 1000 characters".into()));
+    }
+
+    let mut names: HashMap<i32, &str> = HashMap::new();
+    for node in &tree.nodes {
+        let line = parsed.node_lines.get(&node.id).copied();
+        if names.insert(node.id, &node.name).is_some() {
+            errors.push(error(line, format!("node id {} is used more than once", node.id)));
+        }
+        if node.name.trim().is_empty() {
+            errors.push(error(line, "node name must not be empty".into()));
+        }
+        if node.name.chars().count() > 100 {
+            errors.push(error(line, "node name must be at most 100 characters".into()));
+        }
+        if node.summary.as_ref().is_some_and(|summary| summary.chars().count() > 1000) {
+            errors.push(error(line, format!("summary of '{}' must be at most 1000 characters", node.name)));
+        }
+    }
+    for (node, parent) in &tree.edges {
+        let line = parsed.edge_lines.get(&(*node, *parent)).copied();
+        for id in [node, parent] {
+            if !names.contains_key(id) {
+                errors.push(error(line, format!("link refers to unknown node id {}", id)));
+            }
+        }
+        if node == parent {
+            errors.push(error(line, format!("'{}' cannot be its own parent", names.get(node).unwrap_or(&""))));
+        }
+    }
+    if let Some(root_id) = tree.root_id {
+        if !names.contains_key(&root_id) {
+            errors.push(error(None, format!("root refers to unknown node id {}", root_id)));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // Depth first, a link back to a node still being walked closes a cycle
+    let mut branches: HashMap<i32, Vec<i32>> = HashMap::new();
+    for (node, parent) in &tree.edges {
+        branches.entry(*parent).or_default().push(*node);
+    }
+    let mut ids: Vec<i32> = tree.nodes.iter().map(|node| node.id).collect();
+    ids.sort();
+    let mut done: HashSet<i32> = HashSet::new();
+    let mut walking: HashSet<i32> = HashSet::new();
+    for id in ids {
+        if done.contains(&id) {
+            continue;
+        }
+        // (node, index of the next branch to visit)
+        let mut stack: Vec<(i32, usize)> = vec![(id, 0)];
+        walking.insert(id);
+        while let Some((node, next)) = stack.last_mut() {
+            let node = *node;
+            match branches.get(&node).and_then(|children| children.get(*next)) {
+                Some(child) => {
+                    *next += 1;
+                    if walking.contains(child) {
+                        errors.push(error(
+                            parsed.edge_lines.get(&(*child, node)).copied(),
+                            format!("linking '{}' under '{}' makes a cycle", names[child], names[&node])
+                        ));
+                    } else if !done.contains(child) {
+                        walking.insert(*child);
+                        stack.push((*child, 0));
+                    }
+                },
+                None => {
+                    walking.remove(&node);
+                    done.insert(node);
+                    stack.pop();
+                }
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(parsed)
+}
+
+fn parse_json(content: &str) -> Result<Parsed, Vec<ImportError>> {
+    let bundle: Bundle = serde_json::from_str(content)
+        .map_err(|e| vec![error(Some(e.line()), e.to_string())])?;
+    if bundle.version != BUNDLE_VERSION {
+        return Err(vec![error(None, format!("bundle version {} is not supported, expected {}", bundle.version, BUNDLE_VERSION))]);
+    }
+    let mut tree = bundle.tree;
+    let mut seen: HashSet<(i32, i32)> = HashSet::new();
+    tree.edges.retain(|edge| seen.insert(*edge));
+    Ok(Parsed { title: Some(bundle.title), description: bundle.description, tree, ..Parsed::default() })
+}
+
+// "- [x] rest", "* rest", "1. [ ] rest" -> (resolved, rest)
+fn list_item(text: &str) -> Option<(bool, &str)> {
+    let rest = text
+        .strip_prefix("- ")
+        .or_else(|| text.strip_prefix("* "))
+        .or_else(|| text.strip_prefix("+ "))
+        .or_else(|| {
+            let digits = text.find(|c: char| !c.is_ascii_digit())?;
+            text[digits..].strip_prefix(". ").filter(|_| digits > 0)
+        })
+        .or_else(|| ["-", "*", "+"].contains(&text).then_some(""))?;
+    let checkbox = |mark: &str| rest.strip_prefix(mark).map(|rest| rest.strip_prefix(' ').unwrap_or(rest));
+    Some(match (checkbox("[ ]"), checkbox("[x]").or_else(|| checkbox("[X]"))) {
+        (Some(rest), _) => (false, rest),
+        (_, Some(rest)) => (true, rest),
+        _ => (false, rest)
+    })
+}
+
+// Splits "📌 Name" into its icon and name. Only symbols outside ASCII count as an icon.
+fn split_icon(text: &str) -> (Option<String>, &str) {
+    match text.split_once(' ') {
+        Some((first, rest)) if !first.is_empty() && first.chars().all(|c| !c.is_ascii() && !c.is_alphanumeric()) => {
+            (Some(first.into()), rest.trim())
+        },
+        _ => (None, text)
+    }
+}
+
+// "Name <a id="n3"></a>" -> ("Name", Some("n3"))
+fn strip_anchor(text: &str) -> (&str, Option<&str>) {
+    match text.strip_suffix("\"></a>").and_then(|rest| rest.rsplit_once(" <a id=\"")) {
+        Some((rest, anchor)) => (rest.trim_end(), Some(anchor)),
+        None => (text, None)
+    }
+}
+
+// "Name (see above #n3)" -> ("Name", Some("n3")), "Name (see above)" -> ("Name", None)
+fn strip_see_above(text: &str) -> Option<(&str, Option<&str>)> {
+    // An escaped ")" ends the name itself
+    if text.ends_with("\\)") {
+        return None;
+    }
+    if let Some(rest) = text.strip_suffix(SEE_ABOVE) {
+        return Some((rest.trim_end(), None));
+    }
+    let (rest, anchor) = text.strip_suffix(')')?.rsplit_once(" (see above #")?;
+    Some((rest.trim_end(), Some(anchor)))
+}
+
+// Takes off the backslashes export puts at the start of a name and before its last character
+fn unescape_name(name: &str) -> String {
+    let name = name.strip_prefix('\\').unwrap_or(name);
+    match name.chars().last().filter(|c| [')', '*', '_', '>'].contains(c)) {
+        Some(last) if name[..name.len() - last.len_utf8()].ends_with('\\') => {
+            format!("{}{}", &name[..name.len() - last.len_utf8() - 1], last)
+        },
+        _ => name.into()
+    }
+}
+
+// Takes off the backslash export puts before a leading list marker or "#"
+fn unescape_line(text: &str) -> String {
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    match text.strip_prefix('\\') {
+        Some(rest) => rest.into(),
+        None if digits > 0 && text[digits..].starts_with("\\. ") => format!("{}{}", &text[..digits], &text[digits + 1..]),
+        None => text.into()
+    }
+}
+
+fn parse_markdown(content: &str) -> Result<Parsed, Vec<ImportError>> {
+    let mut parsed = Parsed::default();
+    let mut errors: Vec<ImportError> = vec![];
+    let mut description: Vec<String> = vec![];
+    // None once several items above have the name
+    let mut names: HashMap<String, Option<i32>> = HashMap::new();
+    let mut anchors: HashMap<String, i32> = HashMap::new();
+    let mut top_level: Vec<i32> = vec![];
+    // Indentation and node of the items the next one may be nested in
+    let mut stack: Vec<(usize, i32)> = vec![];
+    // The item that indented text below belongs to, None after a reference
+    let mut last_item: Option<(usize, i32)> = None;
+
+    for (index, raw) in content.lines().enumerate() {
+        let line = index + 1;
+        let expanded = raw.replace('\t', "    ");
+        let text = expanded.trim();
+        if text.is_empty() {
+            continue;
+        }
+        let indent = expanded.len() - expanded.trim_start().len();
+
+        if let Some((resolved, rest)) = list_item(text) {
+            while stack.last().is_some_and(|(item_indent, _)| *item_indent >= indent) {
+                stack.pop();
+            }
+            let parent = stack.last().map(|(_, id)| *id);
+            let (rest, anchor) = strip_anchor(rest);
+            let (rest, reference) = match strip_see_above(rest) {
+                Some((rest, reference)) => (rest, Some(reference)),
+                None => (rest, None)
+            };
+            let (rest, optional) = match ["*(optional)*", "_(optional)_", "(optional)"]
+                .iter()
+                .find_map(|marker| rest.strip_suffix(marker))
+            {
+                Some(rest) => (rest.trim_end(), true),
+                None => (rest, false)
+            };
+            let (icon, name) = split_icon(rest.trim());
+            let name = unescape_name(name);
+            let name = name.as_str();
+            if name.is_empty() {
+                errors.push(error(Some(line), "list item has no name".into()));
+                last_item = None;
+                continue;
+            }
+
+            if let Some(reference) = reference {
+                let id = match reference {
+                    Some(anchor) => anchors.get(anchor).copied().ok_or_else(|| {
+                        format!("'{}' is marked (see above #{}) but no item above has that anchor", name, anchor)
+                    }),
+                    None => match names.get(name) {
+                        Some(Some(id)) => Ok(*id),
+                        Some(None) => Err(format!("'{}' is marked (see above) but several items above have that name, point at one with (see above #anchor)", name)),
+                        None => Err(format!("'{}' is marked (see above) but no item above has that name", name))
+                    }
+                };
+                match (id, parent) {
+                    (Ok(id), Some(parent)) => {
+                        parsed.add_edge(id, parent, line);
+                        stack.push((indent, id));
+                    },
+                    (Ok(_), None) => errors.push(error(Some(line), "a (see above) item has to be nested under another item".into())),
+                    (Err(message), _) => errors.push(error(Some(line), message))
+                }
+                last_item = None;
+                continue;
+            }
+
+            let id = parsed.next_id();
+            names.entry(name.into()).and_modify(|named| *named = None).or_insert(Some(id));
+            if let Some(anchor) = anchor {
+                if anchors.insert(anchor.into(), id).is_some() {
+                    errors.push(error(Some(line), format!("anchor '{}' is used more than once", anchor)));
+                }
+            }
+            parsed.add_node(TreeNode { id, name: name.into(), summary: None, optional, resolved, icon }, parent, line);
+            if parent.is_none() {
+                top_level.push(id);
+            }
+            stack.push((indent, id));
+            last_item = Some((indent, id));
+        } else if parsed.tree.nodes.is_empty() && stack.is_empty() {
+            match text.strip_prefix("# ") {
+                Some(title) if parsed.title.is_none() && description.is_empty() => {
+                    parsed.title = Some(title.trim().into());
+                    parsed.title_line = Some(line);
+                },
+                _ if text.starts_with('#') => errors.push(error(Some(line), "only the title may be a heading".into())),
+                _ => description.push(unescape_line(text))
+            }
+        } else if let Some((_, id)) = last_item.filter(|(item_indent, _)| indent > *item_indent) {
+            let text = unescape_line(text);
+            if let Some(node) = parsed.tree.nodes.iter_mut().find(|node| node.id == id) {
+                node.summary = Some(match node.summary.take() {
+                    Some(summary) => format!("{}\n{}", summary, text),
+                    None => text
+                });
+            }
+        } else if text.starts_with('#') {
+            errors.push(error(Some(line), "only the title may be a heading".into()));
+        } else {
+            errors.push(error(Some(line), "expected a list item, or text indented below one".into()));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    if !description.is_empty() {
+        parsed.description = Some(description.join("\n"));
+    }
+    parsed.set_root(top_level);
+    Ok(parsed)
+}
+
+enum Token {
+    Open { name: String, attributes: HashMap<String, String>, empty: bool },
+    Close { name: String },
+    Text(String)
+}
+
+// Just enough XML for OPML: elements, attributes, text, entities. Skips declarations and comments.
+fn xml_tokens(content: &str) -> Result<Vec<(usize, Token)>, ImportError> {
+    let mut tokens: Vec<(usize, Token)> = vec![];
+    let mut line = 1;
+    let mut rest = content;
+    while !rest.is_empty() {
+        let Some(start) = rest.find('<') else {
+            tokens.push((line, Token::Text(decode_entities(rest))));
+            break;
+        };
+        if start > 0 {
+            tokens.push((line, Token::Text(decode_entities(&rest[..start]))));
+            line += rest[..start].matches('\n').count();
+            rest = &rest[start..];
+        }
+
+        let skip_to = |end: &str| rest.find(end).map(|index| index + end.len());
+        let skipped = if rest.starts_with("<?") {
+            skip_to("?>")
+        } else if rest.starts_with("<!--") {
+            skip_to("-->")
+        } else if rest.starts_with("<!") {
+            skip_to(">")
+        } else {
+            None
+        };
+        if rest.starts_with("<?") || rest.starts_with("<!") {
+            let end = skipped.ok_or_else(|| error(Some(line), "unterminated declaration or comment".into()))?;
+            line += rest[..end].matches('\n').count();
+            rest = &rest[end..];
+            continue;
+        }
+
+        let tag_line = line;
+        if let Some(close) = rest.strip_prefix("</") {
+            let end = close.find('>').ok_or_else(|| error(Some(line), "unterminated closing tag".into()))?;
+            tokens.push((tag_line, Token::Close { name: close[..end].trim().into() }));
+            line += close[..end].matches('\n').count();
+            rest = &close[end + 1..];
+            continue;
+        }
+
+        // <name attribute="value" ...> or .../>
+        let mut chars = rest[1..].char_indices().peekable();
+        let name_end = chars
+            .by_ref()
+            .find(|(_, c)| c.is_whitespace() || *c == '>' || *c == '/')
+            .map(|(index, _)| index + 1)
+            .ok_or_else(|| error(Some(line), "unterminated tag".into()))?;
+        let name = rest[1..name_end].to_string();
+        if name.is_empty() {
+            return Err(error(Some(line), "tag has no name".into()));
+        }
+        let mut attributes: HashMap<String, String> = HashMap::new();
+        let mut position = name_end;
+        let (end, empty) = loop {
+            let tail = &rest[position..];
+            let trimmed = tail.trim_start();
+            position += tail.len() - trimmed.len();
+            if trimmed.starts_with("/>") {
+                break (position + 2, true);
+            }
+            if trimmed.starts_with('>') {
+                break (position + 1, false);
+            }
+            let Some(equals) = trimmed.find('=') else {
+                return Err(error(Some(line), format!("malformed attribute in <{}>", name)));
+            };
+            let attribute = trimmed[..equals].trim().to_string();
+            let value = trimmed[equals + 1..].trim_start();
+            let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')
+                .ok_or_else(|| error(Some(line), format!("value of '{}' in <{}> is not quoted", attribute, name)))?;
+            let value_end = value[1..].find(quote)
+                .ok_or_else(|| error(Some(line), format!("value of '{}' in <{}> is not closed", attribute, name)))?;
+            attributes.insert(attribute, decode_entities(&value[1..value_end + 1]));
+            position += (trimmed.len() - value.len()) + value_end + 2;
+        };
+        tokens.push((tag_line, Token::Open { name, attributes, empty }));
+        line += rest[..end].matches('\n').count();
+        rest = &rest[end..];
+    }
+    Ok(tokens)
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => entity
+                    .strip_prefix("#x")
+                    .map(|hex| u32::from_str_radix(hex, 16).ok())
+                    .unwrap_or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                    .and_then(char::from_u32)
+            }?;
+            Some((c, end + 1))
+        });
+        match decoded {
+            Some((c, length)) => {
+                result.push(c);
+                rest = &rest[length..];
+            },
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn parse_opml(content: &str) -> Result<Parsed, Vec<ImportError>> {
+    let tokens = xml_tokens(content).map_err(|e| vec![e])?;
+    let mut parsed = Parsed::default();
+    let mut errors: Vec<ImportError> = vec![];
+    let mut top_level: Vec<i32> = vec![];
+    // Open elements, with the node of each outline (None for a ref, which cannot have branches)
+    let mut open: Vec<(String, Option<i32>, usize)> = vec![];
+    let mut ids: HashMap<String, i32> = HashMap::new();
+    let mut refs: Vec<(String, Option<i32>, usize)> = vec![];
+    let mut title: Option<(String, usize)> = None;
+    let flag = |attributes: &HashMap<String, String>, names: &[&str]| {
+        names.iter().any(|name| attributes.get(*name).is_some_and(|value| value == "true" || value == "1"))
+    };
+
+    for (line, token) in tokens {
+        match token {
+            Token::Open { name, attributes, empty } => {
+                let mut node_id = None;
+                if name == "outline" {
+                    let parent_entry = open.iter().rev().find(|(name, _, _)| name == "outline");
+                    if parent_entry.is_some_and(|(_, id, _)| id.is_none()) {
+                        errors.push(error(Some(line), "an outline with ref cannot have branches".into()));
+                    }
+                    let parent = parent_entry.and_then(|(_, id, _)| *id);
+                    if let Some(reference) = attributes.get("ref") {
+                        if parent.is_none() && parent_entry.is_none() {
+                            errors.push(error(Some(line), "an outline with ref has to be nested under another outline".into()));
+                        }
+                        refs.push((reference.clone(), parent, line));
+                    } else {
+                        let text = attributes.get("text").or(attributes.get("title")).map(|text| text.trim().to_string());
+                        let Some(text) = text.filter(|text| !text.is_empty()) else {
+                            errors.push(error(Some(line), "outline has no text".into()));
+                            if !empty {
+                                open.push((name, None, line));
+                            }
+                            continue;
+                        };
+                        let id = parsed.next_id();
+                        if let Some(opml_id) = attributes.get("id") {
+                            if ids.insert(opml_id.clone(), id).is_some() {
+                                errors.push(error(Some(line), format!("id '{}' is used more than once", opml_id)));
+                            }
+                        }
+                        parsed.add_node(TreeNode {
+                            id,
+                            name: text,
+                            summary: attributes.get("_note").filter(|note| !note.trim().is_empty()).cloned(),
+                            optional: flag(&attributes, &["optional"]),
+                            resolved: flag(&attributes, &["resolved", "_complete"]),
+                            icon: attributes.get("icon").filter(|icon| !icon.trim().is_empty()).cloned()
+                        }, parent, line);
+                        if parent_entry.is_none() {
+                            top_level.push(id);
+                        }
+                        node_id = Some(id);
+                    }
+                }
+                if !empty {
+                    open.push((name, node_id, line));
+                }
+            },
+            Token::Close { name } => match open.pop() {
+                Some((open_name, _, _)) if open_name == name => {},
+                Some((open_name, _, open_line)) => {
+                    return Err(vec![error(Some(line), format!("</{}> closes <{}> from line {}", name, open_name, open_line))]);
+                },
+                None => return Err(vec![error(Some(line), format!("</{}> closes nothing", name))])
+            },
+            Token::Text(text) => {
+                if open.last().is_some_and(|(name, _, _)| name == "title") && title.is_none() {
+                    title = Some((text.trim().to_string(), line)).filter(|(title, _)| !title.is_empty());
+                }
+            }
+        }
+    }
+    if let Some((name, _, line)) = open.last() {
+        errors.push(error(Some(*line), format!("<{}> is never closed", name)));
+    }
+    for (reference, parent, line) in refs {
+        match (ids.get(&reference), parent) {
+            (Some(id), Some(parent)) => parsed.add_edge(*id, parent, line),
+            (None, _) => errors.push(error(Some(line), format!("ref '{}' matches no outline id", reference))),
+            _ => {}
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    if let Some((title, line)) = title {
+        parsed.title = Some(title);
+        parsed.title_line = Some(line);
+    }
+    parsed.set_root(top_level);
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use super::super::export::{markdown, opml};
+    use super::*;
+
+    fn node(id: i32, name: &str) -> TreeNode {
+        TreeNode { id, name: name.into(), summary: None, optional: false, resolved: false, icon: None }
+    }
+
+    // Ids follow the order export writes the nodes in, which is the order import numbers them
+    fn bundle() -> Bundle {
+        let mut root = node(1, "Waves");
+        root.icon = Some("📌".into());
+        root.summary = Some("Steps:\n- read chapter 3\n* take notes\n1. do the exercises\n\\ keep the backslash\n# not a heading".into());
+        let mut optional = node(5, "Review");
+        optional.optional = true;
+        optional.resolved = true;
+        Bundle {
+            version: BUNDLE_VERSION,
+            title: "Physics: waves".into(),
+            description: Some("Plan for the term\n- not a node\n2. not one either".into()),
+            tree: Tree {
+                nodes: vec![root, node(2, "Formulas"), node(3, "Practice"), node(4, "Review"), optional],
+                // Formulas is shared by Waves and Practice, the first Review by Practice and the second Review
+                edges: vec![(2, 1), (3, 1), (5, 1), (2, 3), (4, 3), (4, 5)],
+                root_id: Some(1)
+            }
+        }
+    }
+
+    fn normalized(tree: &Tree) -> Value {
+        let mut tree = tree.clone();
+        tree.nodes.sort_by_key(|node| node.id);
+        tree.edges.sort();
+        serde_json::to_value(tree).unwrap()
+    }
+
+    fn imported(result: Result<Parsed, Vec<ImportError>>) -> Parsed {
+        match result.and_then(|parsed| check(parsed, false)) {
+            Ok(parsed) => parsed,
+            Err(errors) => panic!("import failed: {:?}", messages(&errors))
+        }
+    }
+
+    fn messages(errors: &[ImportError]) -> Vec<(Option<usize>, String)> {
+        errors.iter().map(|error| (error.line, error.message.clone())).collect()
+    }
+
+    fn failed_lines(result: Result<Parsed, Vec<ImportError>>) -> Vec<Option<usize>> {
+        match result.and_then(|parsed| check(parsed, false)) {
+            Ok(_) => panic!("import should have failed"),
+            Err(errors) => errors.iter().map(|error| error.line).collect()
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let bundle = bundle();
+        let parsed = imported(parse_json(&serde_json::to_string(&bundle).unwrap()));
+        assert_eq!(parsed.title.as_deref(), Some("Physics: waves"));
+        assert_eq!(parsed.description, bundle.description);
+        assert_eq!(normalized(&parsed.tree), normalized(&bundle.tree));
+    }
+
+    #[test]
+    fn markdown_round_trip() {
+        let bundle = bundle();
+        let content = markdown(&bundle);
+        let parsed = imported(parse_markdown(&content));
+        assert_eq!(parsed.title.as_deref(), Some("Physics: waves"));
+        assert_eq!(parsed.description, bundle.description);
+        assert_eq!(normalized(&parsed.tree), normalized(&bundle.tree));
+    }
+
+    #[test]
+    fn markdown_round_trip_keeps_names_that_read_as_markup() {
+        let names = [
+            "📌 Pinned", "🔥🔥 Two", "Lab *(optional)*", "Lab _(optional)_", "Maybe (optional)", "(see above)",
+            "Quiz (see above)", "Recap (see above #n2)", "Link <a id=\"n9\"></a>", "Ends in \\)", "Ends in \\*",
+            "\\ Backslash"
+        ];
+        for name in names {
+            // Formulas is written with an anchor and referenced, the last Review is optional
+            let mut bundle = bundle();
+            bundle.tree.nodes[1].name = name.into();
+            bundle.tree.nodes[4].name = name.into();
+            let content = markdown(&bundle);
+            let parsed = imported(parse_markdown(&content));
+            assert_eq!(normalized(&parsed.tree), normalized(&bundle.tree), "{}", content);
+        }
+        let mut bundle = bundle();
+        bundle.tree.nodes[2].name = "📌 Pinned".into();
+        bundle.tree.nodes[3].name = "Quiz (see above)".into();
+        let content = markdown(&bundle);
+        assert!(content.contains("  - [ ] \\📌 Pinned\n"));
+        assert!(content.contains("    - [ ] Quiz (see above\\) <a id=\"n4\"></a>\n"));
+    }
+
+    #[test]
+    fn markdown_points_references_at_anchors() {
+        let content = markdown(&bundle());
+        assert!(content.contains("  - [ ] Formulas <a id=\"n2\"></a>\n"));
+        assert!(content.contains("    - [ ] Formulas (see above #n2)\n"));
+        // The two Reviews share a name, the reference still goes to the first one
+        assert!(content.contains("    - [ ] Review <a id=\"n4\"></a>\n"));
+        assert!(content.contains("  - [x] Review *(optional)*\n    - [ ] Review (see above #n4)\n"));
+        assert!(!content.contains("Practice <a"));
+    }
+
+    #[test]
+    fn markdown_escapes_text_that_reads_as_markup() {
+        let content = markdown(&bundle());
+        assert!(content.contains("\n\\- not a node\n2\\. not one either\n"));
+        assert!(content.contains("\n  \\- read chapter 3\n  \\* take notes\n  1\\. do the exercises\n  \\\\ keep the backslash\n  \\# not a heading\n"));
+    }
+
+    #[test]
+    fn opml_round_trip() {
+        let bundle = bundle();
+        let parsed = imported(parse_opml(&opml(&bundle)));
+        assert_eq!(parsed.title.as_deref(), Some("Physics: waves"));
+        assert_eq!(normalized(&parsed.tree), normalized(&bundle.tree));
+    }
+
+    #[test]
+    fn several_top_level_items_get_a_root() {
+        let parsed = imported(parse_markdown("# Plan\n- [ ] One\n- [ ] Two"));
+        assert_eq!(parsed.tree.root_id, Some(3));
+        assert_eq!(parsed.tree.nodes[2].name, "Plan");
+        assert_eq!(parsed.tree.edges, vec![(1, 3), (2, 3)]);
+    }
+
+    #[test]
+    fn reference_by_unique_name() {
+        let parsed = imported(parse_markdown("- A\n  - B\n  - C\n    - B (see above)"));
+        assert_eq!(parsed.tree.nodes.len(), 3);
+        assert!(parsed.tree.edges.contains(&(2, 3)));
+    }
+
+    #[test]
+    fn reference_by_ambiguous_name_is_rejected() {
+        let result = parse_markdown("- A\n  - B\n  - C\n    - B\n  - D\n    - B (see above)");
+        let Err(errors) = result else {
+            panic!("an ambiguous name should not be resolved");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, Some(6));
+        assert!(errors[0].message.contains("several items above"));
+    }
+
+    #[test]
+    fn unknown_and_duplicate_anchors_are_rejected() {
+        let content = "- A <a id=\"n1\"></a>\n  - B <a id=\"n1\"></a>\n  - C\n    - B (see above #n9)";
+        assert_eq!(failed_lines(parse_markdown(content)), vec![Some(2), Some(4)]);
+    }
+
+    #[test]
+    fn markdown_errors_point_at_their_lines() {
+        let content = "# Title\n- [ ] A\n# Heading\n- [ ]\n- [ ] Missing (see above)\nstray text\n  - [ ] B";
+        let result = parse_markdown(content);
+        let Err(errors) = result else {
+            panic!("the input has errors");
+        };
+        assert_eq!(
+            errors.iter().map(|error| error.line).collect::<Vec<_>>(),
+            vec![Some(3), Some(4), Some(5), Some(6)]
+        );
+    }
+
+    #[test]
+    fn check_reports_cycles_with_their_line() {
+        let result = parse_markdown("- [ ] A\n  - [ ] B\n    - [ ] A (see above)");
+        let Err(errors) = result.and_then(|parsed| check(parsed, false)) else {
+            panic!("the cycle should be found");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, Some(3));
+        assert_eq!(errors[0].message, "linking 'A' under 'B' makes a cycle");
+    }
+
+    #[test]
+    fn check_reports_limits_and_unknown_ids() {
+        let mut long = node(1, &"x".repeat(101));
+        long.summary = Some("y".repeat(1001));
+        let parsed = Parsed {
+            tree: Tree { nodes: vec![long, node(1, " ")], edges: vec![(1, 7)], root_id: Some(8) },
+            ..Parsed::default()
+        };
+        let Err(errors) = check(parsed, false) else {
+            panic!("the tree breaks every rule");
+        };
+        let messages: Vec<String> = errors.into_iter().map(|error| error.message).collect();
+        assert!(messages.contains(&"node name must be at most 100 characters".into()));
+        assert!(messages.iter().any(|message| message.starts_with("summary of")));
+        assert!(messages.contains(&"node id 1 is used more than once".into()));
+        assert!(messages.contains(&"node name must not be empty".into()));
+        assert!(messages.contains(&"link refers to unknown node id 7".into()));
+        assert!(messages.contains(&"root refers to unknown node id 8".into()));
+    }
+
+    #[test]
+    fn list_items() {
+        assert_eq!(list_item("- [x] Done"), Some((true, "Done")));
+        assert_eq!(list_item("* [ ] Open"), Some((false, "Open")));
+        assert_eq!(list_item("+ Plain"), Some((false, "Plain")));
+        assert_eq!(list_item("12. [X] Numbered"), Some((true, "Numbered")));
+        assert_eq!(list_item("- [x]Tight"), Some((true, "Tight")));
+        assert_eq!(list_item("-"), Some((false, "")));
+        assert_eq!(list_item("-dash"), None);
+        assert_eq!(list_item(". dot"), None);
+        assert_eq!(list_item("1.5 kg"), None);
+        assert_eq!(list_item("\\- escaped"), None);
+    }
+
+    #[test]
+    fn icons() {
+        assert_eq!(split_icon("📌 Name"), (Some("📌".into()), "Name"));
+        assert_eq!(split_icon("🔥🔥 Two"), (Some("🔥🔥".into()), "Two"));
+        assert_eq!(split_icon("A Name"), (None, "A Name"));
+        assert_eq!(split_icon("Été long"), (None, "Été long"));
+        assert_eq!(split_icon("📌"), (None, "📌"));
+    }
+
+    #[test]
+    fn lines_are_unescaped() {
+        assert_eq!(unescape_line("\\- item"), "- item");
+        assert_eq!(unescape_line("\\\\ slash"), "\\ slash");
+        assert_eq!(unescape_line("3\\. step"), "3. step");
+        assert_eq!(unescape_line("3\\.5"), "3\\.5");
+        assert_eq!(unescape_line("plain \\- text"), "plain \\- text");
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(decode_entities("a &amp; b &lt;c&gt; &quot;d&apos;"), "a & b <c> \"d'");
+        assert_eq!(decode_entities("&#10;&#x41;&#66;"), "\nAB");
+        assert_eq!(decode_entities("&unknown; & alone &#xZZ;"), "&unknown; & alone &#xZZ;");
+        assert_eq!(decode_entities("&amp;amp;"), "&amp;");
+    }
+
+    #[test]
+    fn tokens_with_their_lines() {
+        let content = "<?xml version=\"1.0\"?>\n<!-- a\n comment -->\n<opml>\n  <outline text='a &amp; b'\n    id=\"1\"/>\n</opml>";
+        let tokens: Vec<(usize, String)> = xml_tokens(content)
+            .unwrap_or_else(|e| panic!("{}", e.message))
+            .into_iter()
+            .filter_map(|(line, token)| match token {
+                Token::Open { name, attributes, empty } => {
+                    let mut attributes: Vec<String> = attributes.into_iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                    attributes.sort();
+                    Some((line, format!("<{} {}{}>", name, attributes.join(" "), if empty { "/" } else { "" })))
+                },
+                Token::Close { name } => Some((line, format!("</{}>", name))),
+                Token::Text(_) => None
+            })
+            .collect();
+        assert_eq!(tokens, vec![
+            (4, "<opml >".to_string()),
+            (5, "<outline id=1 text=a & b/>".to_string()),
+            (7, "</opml>".to_string())
+        ]);
+    }
+
+    #[test]
+    fn malformed_xml() {
+        let message = |content: &str| match xml_tokens(content) {
+            Ok(_) => panic!("'{}' should not tokenize", content),
+            Err(error) => (error.line, error.message)
+        };
+        assert_eq!(message("<opml>\n<outline text=a/>"), (Some(2), "value of 'text' in <outline> is not quoted".into()));
+        assert_eq!(message("<outline text=\"a/>"), (Some(1), "value of 'text' in <outline> is not closed".into()));
+        assert_eq!(message("<opml>\n\n<!-- open"), (Some(3), "unterminated declaration or comment".into()));
+        assert_eq!(message("<outline"), (Some(1), "unterminated tag".into()));
+        assert_eq!(message("</opml"), (Some(1), "unterminated closing tag".into()));
+    }
+
+    #[test]
+    fn malformed_opml() {
+        let errors = |content: &str| match parse_opml(content) {
+            Ok(_) => panic!("'{}' should not import", content),
+            Err(errors) => messages(&errors)
+        };
+        assert_eq!(
+            errors("<opml>\n<body>\n<outline text=\"a\">\n</body>\n</opml>"),
+            vec![(Some(4), "</body> closes <outline> from line 3".into())]
+        );
+        assert_eq!(
+            errors("<opml>\n<body>\n<outline text=\"a\">"),
+            vec![(Some(3), "<outline> is never closed".into())]
+        );
+        assert_eq!(
+            errors("<opml><body>\n<outline text=\"a\" id=\"1\">\n<outline ref=\"2\"/>\n</outline></body></opml>"),
+            vec![(Some(3), "ref '2' matches no outline id".into())]
+        );
+        assert_eq!(
+            errors("<opml><body>\n<outline text=\" \"/>\n<outline text=\"b\" id=\"1\"/>\n<outline text=\"c\" id=\"1\"/>\n</body></opml>"),
+            vec![(Some(2), "outline has no text".into()), (Some(4), "id '1' is used more than once".into())]
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Transaction};
+use crate::debug::{log, LogType::HTTP};
+use crate::mail::mailer::{app_url, send_mail, Mail};
+use super::access::scope;
+use super::api::ApiError;
+use super::auth_user::AuthUser;
+
+/*
+
+    WORKSPACE MEMBERS
+
+    Every workspace has at least one owner, and may be shared with more users:
+
+    VIEWER      -- Reads the tree and its chats.
+    EDITOR      -- Also edits the tree and the workspace details, and chats with the AI.
+    OWNER       -- Also invites, re-roles and removes members, and deletes the workspace.
+
+    Sharing starts with an owner inviting a user by username or email. The invite grants
+    nothing until the invitee accepts it. Members may always leave (or decline an invite)
+    by removing themselves, except the last owner, who has to delete the workspace instead.
+
+*/
+
+// Ordered from least to most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceRole {
+    Viewer,
+    Editor,
+    Owner
+}
+
+impl WorkspaceRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkspaceRole::Viewer => "viewer",
+            WorkspaceRole::Editor => "editor",
+            WorkspaceRole::Owner => "owner"
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<WorkspaceRole> {
+        match value.trim() {
+            "viewer" => Some(WorkspaceRole::Viewer),
+            "editor" => Some(WorkspaceRole::Editor),
+            "owner" => Some(WorkspaceRole::Owner),
+            _ => None
+        }
+    }
+}
+
+// The role the user holds in the workspace, None for strangers and pending invites
+pub async fn workspace_role(workspace_id: i32, user_id: i32, db: &Pool<Postgres>) -> Result<Option<WorkspaceRole>, ApiError> {
+    let role = sqlx::query_scalar!(
+        "SELECT workspace_members.role FROM workspace_members
+        JOIN workspaces ON workspaces.id = workspace_members.workspace_id
+        WHERE workspace_members.workspace_id = $1 AND workspace_members.user_id = $2
+            AND workspace_members.accepted_at IS NOT NULL AND workspaces.deleted_at IS NULL",
+        workspace_id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(role.as_deref().and_then(WorkspaceRole::parse))
+}
+
+// Every workspace, node and chat handler goes through this (or require_node_role) before touching data
+pub async fn require_workspace_role(
+    workspace_id: i32,
+    user_id: i32,
+    required: WorkspaceRole,
+    db: &Pool<Postgres>
+) -> Result<WorkspaceRole, ApiError> {
+    match workspace_role(workspace_id, user_id, db).await? {
+        None => Err(ApiError::UnauthorizedAccess),
+        Some(role) if role < required => Err(ApiError::InsufficientRole),
+        Some(role) => Ok(role)
+    }
+}
+
+// Same check for handlers that only know a node, returns the node's workspace
+pub async fn require_node_role(
+    node_id: i32,
+    user_id: i32,
+    required: WorkspaceRole,
+    db: &Pool<Postgres>
+) -> Result<i32, ApiError> {
+    let workspace_id = sqlx::query_scalar!("SELECT workspace_id FROM nodes WHERE id = $1", node_id)
+        .fetch_optional(db)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?
+        .ok_or(ApiError::ItemNotFound)?;
+    require_workspace_role(workspace_id, user_id, required, db).await?;
+    Ok(workspace_id)
+}
+
+#[derive(Deserialize)]
+pub struct InviteRequest {
+    // Username or email of the user to invite
+    identifier: String,
+    role: WorkspaceRole
+}
+
+#[derive(Deserialize)]
+pub struct UpdateMemberRequest {
+    role: WorkspaceRole
+}
+
+#[derive(Serialize)]
+pub struct Member {
+    user_id: i32,
+    username: String,
+    role: String,
+    invited_by: Option<String>,
+    created: DateTime<Utc>,
+    // None while the invite is pending
+    accepted_at: Option<DateTime<Utc>>
+}
+
+#[derive(Serialize)]
+pub struct Invite {
+    workspace_id: i32,
+    title: String,
+    role: String,
+    invited_by: Option<String>,
+    created: DateTime<Utc>
+}
+
+pub async fn invite(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path(workspace_id): Path<i32>,
+    Json(payload): Json<InviteRequest>
+) -> Result<(StatusCode, Json<Member>), ApiError> {
+    log(HTTP, &format!("UserID <{}> requested INVITE <{}> to workspace <{}>", user.user_id, payload.identifier, workspace_id));
+    require_workspace_role(workspace_id, user.user_id, WorkspaceRole::Owner, &db).await?;
+    let invitee = sqlx::query!(
+        "SELECT id, username, email FROM users WHERE lower(username) = lower($1) OR lower(email) = lower($1)",
+        payload.identifier.trim()
+    )
+    .fetch_optional(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::ItemNotFound)?;
+
+    let member = sqlx::query_as!(
+        Member,
+        r#"INSERT INTO workspace_members (workspace_id, user_id, role, invited_by)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (workspace_id, user_id) DO NOTHING
+        RETURNING user_id, $5::TEXT AS "username!", role,
+            (SELECT username FROM users WHERE id = $4) AS invited_by, created, accepted_at"#,
+        workspace_id,
+        invitee.id,
+        payload.role.as_str(),
+        user.user_id,
+        invitee.username
+    )
+    .fetch_optional(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::AlreadyMember)?;
+
+    let workspace = sqlx::query!(
+        "SELECT workspaces.title, users.username FROM workspaces, users WHERE workspaces.id = $1 AND users.id = $2",
+        workspace_id,
+        user.user_id
+    )
+    .fetch_one(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    send_mail(Mail {
+        to: invitee.email,
+        subject: format!("{} shared a Stackture workspace with you", workspace.username),
+        body: format!(
+            "Hi {},\n\n{} invited you to the workspace \"{}\" as {}.\n\n\
+            Open {} to accept or decline the invite.",
+            invitee.username, workspace.username, workspace.title, payload.role.as_str(), app_url()
+        )
+    });
+    Ok((StatusCode::CREATED, Json(member)))
+}
+
+pub async fn list(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceRead>,
+    Path(workspace_id): Path<i32>
+) -> Result<Json<Vec<Member>>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested FETCH members of workspace <{}>", user.user_id, workspace_id));
+    require_workspace_role(workspace_id, user.user_id, WorkspaceRole::Viewer, &db).await?;
+    let members = sqlx::query_as!(
+        Member,
+        "SELECT workspace_members.user_id, users.username, workspace_members.role,
+            inviters.username AS \"invited_by?\", workspace_members.created, workspace_members.accepted_at
+        FROM workspace_members
+        JOIN users ON users.id = workspace_members.user_id
+        LEFT JOIN users AS inviters ON inviters.id = workspace_members.invited_by
+        WHERE workspace_members.workspace_id = $1
+        ORDER BY workspace_members.created",
+        workspace_id
+    )
+    .fetch_all(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(Json(members))
+}
+
+pub async fn invites(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceRead>
+) -> Result<Json<Vec<Invite>>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested FETCH workspace invites", user.user_id));
+    let invites = sqlx::query_as!(
+        Invite,
+        "SELECT workspaces.id AS workspace_id, workspaces.title, workspace_members.role,
+            inviters.username AS \"invited_by?\", workspace_members.created
+        FROM workspace_members
+        JOIN workspaces ON workspaces.id = workspace_members.workspace_id
+        LEFT JOIN users AS inviters ON inviters.id = workspace_members.invited_by
+        WHERE workspace_members.user_id = $1 AND workspace_members.accepted_at IS NULL
+            AND workspaces.deleted_at IS NULL
+        ORDER BY workspace_members.created DESC",
+        user.user_id
+    )
+    .fetch_all(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(Json(invites))
+}
+
+pub async fn accept(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path(workspace_id): Path<i32>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested ACCEPT invite to workspace <{}>", user.user_id, workspace_id));
+    let result = sqlx::query!(
+        "UPDATE workspace_members SET accepted_at = now()
+        WHERE workspace_id = $1 AND user_id = $2 AND accepted_at IS NULL
+            AND EXISTS (SELECT 1 FROM workspaces WHERE id = $1 AND deleted_at IS NULL)",
+        workspace_id,
+        user.user_id
+    )
+    .execute(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::ItemNotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn update(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path((workspace_id, member_id)): Path<(i32, i32)>,
+    Json(payload): Json<UpdateMemberRequest>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested SET role of member <{}> in workspace <{}>", user.user_id, member_id, workspace_id));
+    require_workspace_role(workspace_id, user.user_id, WorkspaceRole::Owner, &db).await?;
+    member_exists(workspace_id, member_id, &db).await?;
+    // Demoting an owner must leave another accepted owner behind
+    let mut tx = db.begin().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    lock_owners(workspace_id, &mut tx).await?;
+    let result = sqlx::query!(
+        "UPDATE workspace_members SET role = $3
+        WHERE workspace_id = $1 AND user_id = $2
+            AND ($3 = 'owner' OR role <> 'owner' OR accepted_at IS NULL OR EXISTS (
+                SELECT 1 FROM workspace_members AS owners
+                WHERE owners.workspace_id = $1 AND owners.user_id <> $2
+                    AND owners.role = 'owner' AND owners.accepted_at IS NOT NULL
+            ))",
+        workspace_id,
+        member_id,
+        payload.role.as_str()
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::LastOwner);
+    }
+    tx.commit().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Owners remove anyone, everyone else only themselves (leaving, or declining an invite)
+pub async fn remove(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path((workspace_id, member_id)): Path<(i32, i32)>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested REMOVE member <{}> from workspace <{}>", user.user_id, member_id, workspace_id));
+    if member_id != user.user_id {
+        require_workspace_role(workspace_id, user.user_id, WorkspaceRole::Owner, &db).await?;
+    }
+    member_exists(workspace_id, member_id, &db).await?;
+    let mut tx = db.begin().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    lock_owners(workspace_id, &mut tx).await?;
+    let result = sqlx::query!(
+        "DELETE FROM workspace_members
+        WHERE workspace_id = $1 AND user_id = $2
+            AND (role <> 'owner' OR accepted_at IS NULL OR EXISTS (
+                SELECT 1 FROM workspace_members AS owners
+                WHERE owners.workspace_id = $1 AND owners.user_id <> $2
+                    AND owners.role = 'owner' AND owners.accepted_at IS NOT NULL
+            ))",
+        workspace_id,
+        member_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::LastOwner);
+    }
+    tx.commit().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// The last owner check reads the other owners, which two owners demoting or removing each other
+// at the same time would both still see. Holding the owner rows makes the second one wait and
+// check again after the first is done.
+async fn lock_owners(workspace_id: i32, tx: &mut Transaction<'_, Postgres>) -> Result<(), ApiError> {
+    sqlx::query!(
+        "SELECT user_id FROM workspace_members WHERE workspace_id = $1 AND role = 'owner' FOR UPDATE",
+        workspace_id
+    )
+    .fetch_all(&mut **tx)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(())
+}
+
+async fn member_exists(workspace_id: i32, user_id: i32, db: &Pool<Postgres>) -> Result<(), ApiError> {
+    let exists = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM workspace_members WHERE workspace_id = $1 AND user_id = $2) AS "exists!""#,
+        workspace_id,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if !exists {
+        return Err(ApiError::ItemNotFound);
+    }
+    Ok(())
+}
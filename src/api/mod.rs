@@ -1,4 +1,20 @@
 pub mod node;
+pub mod pagination;
 pub mod atomic;
 pub mod workspace;
-pub mod api;
\ No newline at end of file
+pub mod api;
+pub mod access;
+pub mod admin;
+pub mod audit;
+pub mod auth_user;
+pub mod export;
+pub mod group;
+pub mod import;
+pub mod member;
+pub mod progress;
+pub mod share;
+pub mod template;
+pub mod trash;
+pub mod tree;
+pub mod validation;
+pub mod client;
\ No newline at end of file
@@ -1,8 +1,12 @@
 use axum::{extract::State, http::StatusCode, response::{IntoResponse, Response}, Json};
-use axum_extra::{headers::{authorization::Bearer, Authorization}, TypedHeader};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sqlx::{Pool, Postgres};
-use super::{api::extract_token_data, atomic::{add_node, borrow_node, create_node, delete_node, drop_node, take_node}};
+use super::audit::{self, AuditEvent};
+use super::client::ClientInfo;
+use super::member::{require_node_role, require_workspace_role, WorkspaceRole};
+use super::validation::{Validate, ValidJson, ValidationErrors, Validator};
+use super::{access::scope, auth_user::AuthUser, atomic::{add_node, borrow_node, create_node, delete_node, drop_node, take_node, NodeOperationError}};
 
 #[derive(Serialize, Deserialize)]
 pub struct CreateRequest {
@@ -11,6 +15,16 @@ pub struct CreateRequest {
     summary: String
 }
 
+impl Validate for CreateRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .required("name", &self.name)
+            .max_length("name", &self.name, 100)
+            .max_length("summary", &self.summary, 1000)
+            .finish()
+    }
+}
+
 #[derive(Serialize)]
 pub struct CreateResponse {
     node_id: i32
@@ -24,6 +38,16 @@ pub struct AddRequest {
     summary: String
 }
 
+impl Validate for AddRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .required("name", &self.name)
+            .max_length("name", &self.name, 100)
+            .max_length("summary", &self.summary, 1000)
+            .finish()
+    }
+}
+
 #[derive(Serialize)]
 pub struct AddResponse {
     node_id: i32
@@ -66,10 +90,12 @@ pub struct DeleteResponse;
 
 pub async fn create(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
-    Json(payload): Json<CreateRequest>
+    user: AuthUser<scope::NodeWrite>,
+    ValidJson(payload): ValidJson<CreateRequest>
 ) -> Result<Json<CreateResponse>, Response> {
-    extract_token_data(auth).map_err(IntoResponse::into_response)?;
+    require_workspace_role(payload.workspace_id, user.user_id, WorkspaceRole::Editor, &db)
+        .await
+        .map_err(IntoResponse::into_response)?;
     let node_id = create_node(
         payload.workspace_id,
         &payload.name,
@@ -83,10 +109,15 @@ pub async fn create(
 
 pub async fn add(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
-    Json(payload): Json<AddRequest>
+    user: AuthUser<scope::NodeWrite>,
+    ValidJson(payload): ValidJson<AddRequest>
 ) -> Result<Json<AddResponse>, Response> {
-    extract_token_data(auth).map_err(IntoResponse::into_response)?;
+    require_workspace_role(payload.workspace_id, user.user_id, WorkspaceRole::Editor, &db)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    require_node_role(payload.node_id, user.user_id, WorkspaceRole::Editor, &db)
+        .await
+        .map_err(IntoResponse::into_response)?;
     let node_id = add_node(
         payload.workspace_id,
         payload.node_id,
@@ -99,12 +130,31 @@ pub async fn add(
     Ok(Json(AddResponse { node_id }))
 }
 
+// Both ends of the edge have to be editable, and in the same workspace
+async fn require_edge_role(
+    node_id: i32,
+    branch_id: i32,
+    user_id: i32,
+    db: &Pool<Postgres>
+) -> Result<(), Response> {
+    let workspace_id = require_node_role(node_id, user_id, WorkspaceRole::Editor, db)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let branch_workspace_id = require_node_role(branch_id, user_id, WorkspaceRole::Editor, db)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    if workspace_id != branch_workspace_id {
+        return Err(NodeOperationError::ForbiddenLink.into_response());
+    }
+    Ok(())
+}
+
 pub async fn borrow(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    user: AuthUser<scope::NodeWrite>,
     Json(payload): Json<BorrowRequest>
 ) -> Result<StatusCode, Response> {
-    extract_token_data(auth).map_err(IntoResponse::into_response)?;
+    require_edge_role(payload.node_id, payload.branch_id, user.user_id, &db).await?;
     borrow_node(
         payload.node_id,
         payload.branch_id,
@@ -117,10 +167,10 @@ pub async fn borrow(
 
 pub async fn drop(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    user: AuthUser<scope::NodeWrite>,
     Json(payload): Json<DropRequest>
 ) -> Result<StatusCode, Response> {
-    extract_token_data(auth).map_err(IntoResponse::into_response)?;
+    require_edge_role(payload.node_id, payload.branch_id, user.user_id, &db).await?;
     drop_node(
         payload.node_id,
         payload.branch_id,
@@ -133,13 +183,13 @@ pub async fn drop(
 
 pub async fn take(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    user: AuthUser<scope::NodeWrite>,
     Json(payload): Json<TakeRequest>
 ) -> Result<StatusCode, Response> {
-    extract_token_data(auth).map_err(IntoResponse::into_response)?;
+    require_edge_role(payload.node_id, payload.branch_id, user.user_id, &db).await?;
     take_node(
         payload.node_id,
-        payload.node_id,
+        payload.branch_id,
         &db
     )
     .await
@@ -149,15 +199,19 @@ pub async fn take(
 
 pub async fn delete(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    client: ClientInfo,
+    user: AuthUser<scope::NodeWrite>,
     Json(payload): Json<DeleteRequest>
 ) -> Result<StatusCode, Response> {
-    extract_token_data(auth).map_err(IntoResponse::into_response)?;
+    require_node_role(payload.node_id, user.user_id, WorkspaceRole::Editor, &db)
+        .await
+        .map_err(IntoResponse::into_response)?;
     delete_node(
         payload.node_id,
         &db
     )
     .await
     .map_err(IntoResponse::into_response)?;
+    audit::record(&db, &client, AuditEvent::NodeDeleted, Some(user.user_id), json!({ "node_id": payload.node_id })).await;
     Ok(StatusCode::NO_CONTENT)
 }
@@ -0,0 +1,38 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use super::api::ApiError;
+
+// Position after the last item of a page: its sort key and id (the tie breaker).
+// Opaque to clients, who only pass it back as ?cursor= to get the next page.
+#[derive(Serialize, Deserialize)]
+pub struct Cursor {
+    // What the page was sorted by, a cursor from another sort is meaningless
+    pub sort: String,
+    pub key: String,
+    pub id: i32
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    pub fn decode(value: &str, sort: &str) -> Result<Cursor, ApiError> {
+        let cursor: Cursor = URL_SAFE_NO_PAD
+            .decode(value)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or(ApiError::InvalidCursor)?;
+        if cursor.sort != sort {
+            return Err(ApiError::InvalidCursor);
+        }
+        Ok(cursor)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    // None on the last page
+    pub next_cursor: Option<String>
+}
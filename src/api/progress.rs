@@ -0,0 +1,502 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use crate::debug::{log, LogType::HTTP};
+use super::access::scope;
+use super::api::ApiError;
+use super::auth_user::AuthUser;
+use super::member::{require_workspace_role, WorkspaceRole};
+use super::tree::{Tree, TreeNode};
+
+/*
+
+    PROGRESS
+
+    How far along a workspace is, overall and for the subtree below every node reachable
+    from the root. Progress is counted in leaves, the concrete problems at the bottom:
+
+    REQUIRED LEAF   -- A node reached without passing an optional node, none of whose
+                       branches are required. Optional nodes never block, so a node with
+                       only optional branches is itself the thing left to do.
+    RESOLVED        -- A leaf counts as resolved when it or a node above it inside the subtree
+                       is, solving a problem settles everything it was broken down into.
+
+    The tree is a DAG, a node shared by several parents is still counted once per subtree.
+    The node a subtree starts at always counts as required, so an optional subtree shows
+    its own progress. completion is the resolved share of the required leaves in percent,
+    100 when nothing is required, as in a workspace without a root.
+
+    Subtrees are left out (null) above MAX_SUBTREE_NODES reachable nodes, counting them takes
+    memory that grows with the square of the node count.
+
+*/
+
+const MAX_SUBTREE_NODES: usize = 5000;
+
+#[derive(Serialize, Default)]
+pub struct Progress {
+    // Distinct nodes in the subtree, the node itself included
+    nodes: usize,
+    leaves: usize,
+    required_leaves: usize,
+    resolved_required_leaves: usize,
+    remaining_required_leaves: usize,
+    completion: f64
+}
+
+impl Progress {
+    // Fills in what follows from the counts
+    fn finish(mut self) -> Progress {
+        self.remaining_required_leaves = self.required_leaves - self.resolved_required_leaves;
+        self.completion = match self.required_leaves {
+            0 => 100.0,
+            total => (self.resolved_required_leaves as f64 * 1000.0 / total as f64).round() / 10.0
+        };
+        self
+    }
+}
+
+#[derive(Serialize)]
+pub struct SubtreeProgress {
+    node_id: i32,
+    name: String,
+    // Shortest distance from the root
+    depth: usize,
+    optional: bool,
+    resolved: bool,
+    #[serde(flatten)]
+    progress: Progress
+}
+
+#[derive(Serialize)]
+pub struct WorkspaceProgress {
+    workspace_id: i32,
+    root_id: Option<i32>,
+    // Every node of the workspace, also those not connected to the root
+    total_nodes: usize,
+    resolved_nodes: usize,
+    optional_nodes: usize,
+    // Deepest node below the root
+    depth: usize,
+    #[serde(flatten)]
+    progress: Progress,
+    // Ordered by depth, then node id. None when the workspace is too big.
+    subtrees: Option<Vec<SubtreeProgress>>
+}
+
+pub async fn workspace_progress(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceRead>,
+    Path(id): Path<i32>
+) -> Result<Json<WorkspaceProgress>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested PROGRESS of workspace <{}>", user.user_id, id));
+    require_workspace_role(id, user.user_id, WorkspaceRole::Viewer, &db).await?;
+    let mut conn = db.acquire().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    let tree = Tree::load(id, &mut conn).await?;
+    // The connection goes back to the pool before counting, which needs none
+    drop(conn);
+    // Big workspaces take a while to count, that has no place on the async workers
+    let progress = tokio::task::spawn_blocking(move || summarize(id, &tree))
+        .await
+        .map_err(|_| ApiError::InternalError)?;
+    Ok(Json(progress))
+}
+
+fn summarize(workspace_id: i32, tree: &Tree) -> WorkspaceProgress {
+    let graph = Graph::new(tree);
+    let root_id = tree.root_id.filter(|root_id| graph.nodes.contains_key(root_id));
+    let depths = root_id.map(|root_id| graph.depths(root_id)).unwrap_or_default();
+    let subtrees = match root_id {
+        Some(root_id) if depths.len() <= MAX_SUBTREE_NODES => {
+            let mut progress = graph.subtree_progress(root_id);
+            let mut reachable: Vec<(usize, i32)> = depths.iter().map(|(id, depth)| (*depth, *id)).collect();
+            reachable.sort();
+            Some(reachable
+                .into_iter()
+                .map(|(depth, node_id)| {
+                    let node = graph.nodes[&node_id];
+                    SubtreeProgress {
+                        node_id,
+                        name: node.name.clone(),
+                        depth,
+                        optional: node.optional,
+                        resolved: node.resolved,
+                        progress: progress.remove(&node_id).unwrap_or_default()
+                    }
+                })
+                .collect())
+        },
+        Some(_) => None,
+        None => Some(vec![])
+    };
+
+    WorkspaceProgress {
+        workspace_id,
+        root_id,
+        total_nodes: tree.nodes.len(),
+        resolved_nodes: tree.nodes.iter().filter(|node| node.resolved).count(),
+        optional_nodes: tree.nodes.iter().filter(|node| node.optional).count(),
+        depth: depths.values().copied().max().unwrap_or_default(),
+        progress: match root_id {
+            Some(root_id) => graph.progress(root_id),
+            None => Progress::default().finish()
+        },
+        subtrees
+    }
+}
+
+struct Graph<'a> {
+    nodes: HashMap<i32, &'a TreeNode>,
+    branches: HashMap<i32, Vec<i32>>
+}
+
+impl<'a> Graph<'a> {
+    fn new(tree: &'a Tree) -> Graph<'a> {
+        let nodes: HashMap<i32, &TreeNode> = tree.nodes.iter().map(|node| (node.id, node)).collect();
+        let mut branches: HashMap<i32, Vec<i32>> = HashMap::new();
+        for (node, parent) in &tree.edges {
+            if nodes.contains_key(node) && nodes.contains_key(parent) {
+                branches.entry(*parent).or_default().push(*node);
+            }
+        }
+        Graph { nodes, branches }
+    }
+
+    fn branches(&self, id: i32) -> &[i32] {
+        self.branches.get(&id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    // Breadth first, so every reachable node gets its shortest distance from the root
+    fn depths(&self, root_id: i32) -> HashMap<i32, usize> {
+        let mut depths: HashMap<i32, usize> = HashMap::new();
+        if !self.nodes.contains_key(&root_id) {
+            return depths;
+        }
+        let mut queue: VecDeque<(i32, usize)> = VecDeque::from([(root_id, 0)]);
+        depths.insert(root_id, 0);
+        while let Some((id, depth)) = queue.pop_front() {
+            for branch in self.branches(id) {
+                if !depths.contains_key(branch) {
+                    depths.insert(*branch, depth + 1);
+                    queue.push_back((*branch, depth + 1));
+                }
+            }
+        }
+        depths
+    }
+
+    fn progress(&self, start: i32) -> Progress {
+        // A node is walked again when it is reached in a better state than before: through
+        // required nodes only, or below a resolved one. That is at most three times per node.
+        let mut required: HashSet<i32> = HashSet::new();
+        let mut resolved: HashSet<i32> = HashSet::new();
+        let mut seen: HashSet<i32> = HashSet::new();
+        let mut stack: Vec<(i32, bool, bool)> = vec![(start, true, false)];
+        while let Some((id, is_required, above_resolved)) = stack.pop() {
+            let is_resolved = above_resolved || self.nodes[&id].resolved;
+            let improved = (is_required && !required.contains(&id)) || (is_resolved && !resolved.contains(&id));
+            if seen.contains(&id) && !improved {
+                continue;
+            }
+            seen.insert(id);
+            if is_required {
+                required.insert(id);
+            }
+            if is_resolved {
+                resolved.insert(id);
+            }
+            let is_required = required.contains(&id);
+            let is_resolved = resolved.contains(&id);
+            for branch in self.branches(id) {
+                stack.push((*branch, is_required && !self.nodes[branch].optional, is_resolved));
+            }
+        }
+
+        let mut progress = Progress { nodes: seen.len(), ..Progress::default() };
+        for id in &seen {
+            if self.branches(*id).is_empty() {
+                progress.leaves += 1;
+            }
+            if required.contains(id) && !self.blocked(*id) {
+                progress.required_leaves += 1;
+                if resolved.contains(id) {
+                    progress.resolved_required_leaves += 1;
+                }
+            }
+        }
+        progress.finish()
+    }
+
+    // A node with a required branch is not a leaf of its own, it is done when its branches are
+    fn blocked(&self, id: i32) -> bool {
+        self.branches(id).iter().any(|branch| !self.nodes[branch].optional)
+    }
+
+    // progress() of every node reachable from the root at once, in time and memory of about
+    // nodes * nodes / 64 words instead of a walk per node. Every node gets three sets over the
+    // reachable nodes: all it reaches, those it reaches through required nodes only and those
+    // below a resolved node on the way. A node's sets are the union of its branches' sets, so
+    // filling them in depth first post order takes one pass. Cycles, which links can make,
+    // leave some sets short after it, the passes repeat until nothing changes.
+    fn subtree_progress(&self, root_id: i32) -> HashMap<i32, Progress> {
+        let order = self.post_order(root_id);
+        let index: HashMap<i32, usize> = order.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        let mut reach = NodeSets::new(order.len());
+        let mut required = NodeSets::new(order.len());
+        for i in 0..order.len() {
+            reach.insert(i, i);
+            required.insert(i, i);
+        }
+        self.close(&order, &index, &mut reach, |_, _| true);
+        self.close(&order, &index, &mut required, |_, branch| !self.nodes[&branch].optional);
+        // Everything below a resolved node counts as resolved, it passes its whole reach up
+        let mut resolved = NodeSets::new(order.len());
+        for (i, id) in order.iter().enumerate() {
+            if self.nodes[id].resolved {
+                resolved.union_from(i, &reach, i);
+            }
+        }
+        self.close(&order, &index, &mut resolved, |id, _| !self.nodes[&id].resolved);
+
+        // Nodes that are leaves, and nodes that are required leaves when reached as required
+        let mut leaves = vec![0u64; reach.words];
+        let mut unblocked = vec![0u64; reach.words];
+        for (i, id) in order.iter().enumerate() {
+            if self.branches(*id).is_empty() {
+                leaves[i / 64] |= 1 << (i % 64);
+            }
+            if !self.blocked(*id) {
+                unblocked[i / 64] |= 1 << (i % 64);
+            }
+        }
+        order
+            .iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let progress = Progress {
+                    nodes: reach.count(i, &[]),
+                    leaves: reach.count(i, &[&leaves]),
+                    required_leaves: required.count(i, &[&unblocked]),
+                    resolved_required_leaves: required.count(i, &[&unblocked, resolved.set(i)]),
+                    ..Progress::default()
+                };
+                (*id, progress.finish())
+            })
+            .collect()
+    }
+
+    // Every node reachable from the root, each after all of its branches unless a cycle leads back
+    fn post_order(&self, root_id: i32) -> Vec<i32> {
+        let mut order: Vec<i32> = vec![];
+        let mut seen: HashSet<i32> = HashSet::from([root_id]);
+        // (node, index of the next branch to visit)
+        let mut stack: Vec<(i32, usize)> = vec![(root_id, 0)];
+        while let Some((id, next)) = stack.last_mut() {
+            let id = *id;
+            match self.branches(id).get(*next) {
+                Some(branch) => {
+                    *next += 1;
+                    if seen.insert(*branch) {
+                        stack.push((*branch, 0));
+                    }
+                },
+                None => {
+                    order.push(id);
+                    stack.pop();
+                }
+            }
+        }
+        order
+    }
+
+    // Adds the set of each followed branch to its parent's, until no set grows
+    fn close(&self, order: &[i32], index: &HashMap<i32, usize>, sets: &mut NodeSets, follow: impl Fn(i32, i32) -> bool) {
+        loop {
+            let mut changed = false;
+            for (i, id) in order.iter().enumerate() {
+                for branch in self.branches(*id) {
+                    if follow(*id, *branch) {
+                        changed |= sets.union(i, index[branch]);
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+}
+
+// One bitset over the node indexes per node, all in one buffer
+struct NodeSets {
+    words: usize,
+    bits: Vec<u64>
+}
+
+impl NodeSets {
+    fn new(len: usize) -> NodeSets {
+        let words = len.div_ceil(64);
+        NodeSets { words, bits: vec![0; words * len] }
+    }
+
+    fn set(&self, set: usize) -> &[u64] {
+        &self.bits[set * self.words..(set + 1) * self.words]
+    }
+
+    fn insert(&mut self, set: usize, index: usize) {
+        self.bits[set * self.words + index / 64] |= 1 << (index % 64);
+    }
+
+    // Adds set from to set into, true when that added anything
+    fn union(&mut self, into: usize, from: usize) -> bool {
+        let mut changed = false;
+        for word in 0..self.words {
+            let added = self.bits[from * self.words + word] & !self.bits[into * self.words + word];
+            if added != 0 {
+                self.bits[into * self.words + word] |= added;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn union_from(&mut self, into: usize, other: &NodeSets, from: usize) {
+        for (word, bits) in other.set(from).iter().enumerate() {
+            self.bits[into * self.words + word] |= bits;
+        }
+    }
+
+    // Size of the set after intersecting it with every mask
+    fn count(&self, set: usize, masks: &[&[u64]]) -> usize {
+        self.set(set)
+            .iter()
+            .enumerate()
+            .map(|(word, bits)| masks.iter().fold(*bits, |bits, mask| bits & mask[word]).count_ones() as usize)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use super::*;
+
+    // (id, optional, resolved) and (node, parent) links
+    fn tree(nodes: &[(i32, bool, bool)], edges: &[(i32, i32)]) -> Tree {
+        Tree {
+            nodes: nodes
+                .iter()
+                .map(|(id, optional, resolved)| TreeNode {
+                    id: *id,
+                    name: format!("node {}", id),
+                    summary: None,
+                    optional: *optional,
+                    resolved: *resolved,
+                    icon: None
+                })
+                .collect(),
+            edges: edges.to_vec(),
+            root_id: Some(1)
+        }
+    }
+
+    // nodes, leaves, required leaves, resolved required leaves, completion
+    fn counts(progress: &Progress) -> (usize, usize, usize, usize, f64) {
+        (progress.nodes, progress.leaves, progress.required_leaves, progress.resolved_required_leaves, progress.completion)
+    }
+
+    #[test]
+    fn optional_only_branches_leave_the_node_itself_to_do() {
+        let tree = tree(&[(1, false, false), (2, true, false), (3, true, true)], &[(2, 1), (3, 1)]);
+        let graph = Graph::new(&tree);
+        assert_eq!(counts(&graph.progress(1)), (3, 2, 1, 0, 0.0));
+        // An optional subtree counts its own start as required
+        assert_eq!(counts(&graph.progress(2)), (1, 1, 1, 0, 0.0));
+        assert_eq!(counts(&graph.progress(3)), (1, 1, 1, 1, 100.0));
+    }
+
+    #[test]
+    fn optional_nodes_do_not_make_their_branches_required() {
+        // 4 is below the optional 2 and below 3, which has the optional 5 as well
+        let tree = tree(
+            &[(1, false, false), (2, true, false), (3, false, false), (4, false, false), (5, true, false), (6, false, false)],
+            &[(2, 1), (3, 1), (6, 2), (4, 3), (5, 3)]
+        );
+        let graph = Graph::new(&tree);
+        assert_eq!(counts(&graph.progress(1)), (6, 3, 1, 0, 0.0));
+    }
+
+    #[test]
+    fn shared_children_count_once() {
+        let tree = tree(
+            &[(1, false, false), (2, false, false), (3, false, false), (4, false, true), (5, false, false)],
+            &[(2, 1), (3, 1), (4, 2), (4, 3), (5, 3)]
+        );
+        let graph = Graph::new(&tree);
+        assert_eq!(counts(&graph.progress(1)), (5, 2, 2, 1, 50.0));
+        assert_eq!(counts(&graph.progress(2)), (2, 1, 1, 1, 100.0));
+        assert_eq!(counts(&graph.progress(3)), (3, 2, 2, 1, 50.0));
+    }
+
+    #[test]
+    fn a_resolved_ancestor_resolves_its_subtree() {
+        let tree = tree(
+            &[(1, false, false), (2, false, true), (3, false, false), (4, false, false), (5, false, false)],
+            &[(2, 1), (3, 2), (4, 2), (5, 1)]
+        );
+        let graph = Graph::new(&tree);
+        assert_eq!(counts(&graph.progress(1)), (5, 3, 3, 2, 66.7));
+        // Only ancestors inside the subtree count
+        assert_eq!(counts(&graph.progress(3)), (1, 1, 1, 0, 0.0));
+    }
+
+    #[test]
+    fn a_shared_child_is_resolved_through_any_resolved_parent() {
+        let tree = tree(
+            &[(1, false, false), (2, false, true), (3, false, false), (4, false, false)],
+            &[(2, 1), (3, 1), (4, 2), (4, 3)]
+        );
+        let graph = Graph::new(&tree);
+        assert_eq!(counts(&graph.progress(1)), (4, 1, 1, 1, 100.0));
+        assert_eq!(counts(&graph.progress(3)), (2, 1, 1, 0, 0.0));
+    }
+
+    #[test]
+    fn subtrees_match_a_walk_per_node() {
+        let trees = [
+            tree(
+                &[(1, false, false), (2, true, false), (3, false, true), (4, false, false), (5, true, true), (6, false, false), (7, false, false)],
+                &[(2, 1), (3, 1), (4, 2), (4, 3), (5, 3), (6, 4), (7, 5), (6, 1)]
+            ),
+            // Links do not prevent cycles: 2 -> 3 -> 4 -> 2, with an optional node on the way
+            tree(
+                &[(1, false, false), (2, false, false), (3, true, false), (4, false, true), (5, false, false)],
+                &[(2, 1), (3, 2), (4, 3), (2, 4), (5, 4), (5, 1)]
+            )
+        ];
+        for tree in &trees {
+            let graph = Graph::new(tree);
+            let subtrees = graph.subtree_progress(1);
+            assert_eq!(subtrees.len(), tree.nodes.len());
+            for (id, progress) in &subtrees {
+                let walked = graph.progress(*id);
+                assert_eq!(json!(progress), json!(walked), "subtree of node {}", id);
+            }
+        }
+    }
+
+    #[test]
+    fn a_workspace_without_root_has_nothing_left() {
+        let mut tree = tree(&[(1, false, false)], &[]);
+        tree.root_id = None;
+        let progress: Value = json!(summarize(7, &tree));
+        assert_eq!(progress["completion"], json!(100.0));
+        assert_eq!(progress["required_leaves"], json!(0));
+        assert_eq!(progress["subtrees"], json!([]));
+        assert_eq!(progress["total_nodes"], json!(1));
+    }
+}
@@ -0,0 +1,209 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use crate::auth::token::{generate_token, hash_token};
+use crate::chat::api::{workspace_transcripts, Message};
+use crate::debug::{log, LogType::HTTP};
+use crate::mail::mailer::app_url;
+use super::access::scope;
+use super::api::ApiError;
+use super::audit::{self, AuditEvent};
+use super::auth_user::AuthUser;
+use super::client::ClientInfo;
+use super::member::{require_workspace_role, WorkspaceRole};
+use super::validation::{Validate, ValidJson, ValidationErrors, Validator};
+use super::workspace::{fetch_nodes, WorkspaceNode};
+
+/*
+
+    SHARE LINKS
+
+    Owners publish a workspace read-only to anyone holding the link, no account needed.
+    As with personal access tokens only the hash of the token is kept, so a link is shown
+    once when it is created. It works until it expires (if an expiry was set) or is revoked.
+    Unknown, expired and revoked links all look the same to the reader: NotFound.
+
+*/
+
+#[derive(Deserialize)]
+pub struct CreateShareRequest {
+    #[serde(default)]
+    include_chats: bool,
+    expires_in_days: Option<i64>
+}
+
+impl Validate for CreateShareRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut validator = Validator::new();
+        if let Some(days) = self.expires_in_days {
+            validator.range("expires_in_days", days, 1, 3650);
+        }
+        validator.finish()
+    }
+}
+
+#[derive(Serialize)]
+pub struct CreatedShare {
+    id: i32,
+    token: String,
+    url: String,
+    include_chats: bool,
+    expires_at: Option<DateTime<Utc>>
+}
+
+#[derive(Serialize)]
+pub struct ShareSummary {
+    id: i32,
+    include_chats: bool,
+    created_by: Option<String>,
+    created: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>
+}
+
+#[derive(Serialize)]
+pub struct SharedChat {
+    // None for the chat of the whole workspace
+    node_id: Option<i32>,
+    messages: Vec<Message>
+}
+
+#[derive(Serialize)]
+pub struct SharedWorkspace {
+    title: String,
+    description: Option<String>,
+    root_id: Option<i32>,
+    nodes: Vec<WorkspaceNode>,
+    // Only when the link was created with include_chats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chats: Option<Vec<SharedChat>>
+}
+
+pub async fn create(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path(workspace_id): Path<i32>,
+    ValidJson(payload): ValidJson<CreateShareRequest>
+) -> Result<(StatusCode, Json<CreatedShare>), Response> {
+    log(HTTP, &format!("UserID <{}> requested CREATE share link for workspace <{}>", user.user_id, workspace_id));
+    require_workspace_role(workspace_id, user.user_id, WorkspaceRole::Owner, &db)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let token = generate_token();
+    let created = sqlx::query!(
+        "INSERT INTO share_links (workspace_id, token_hash, include_chats, created_by, expires_at)
+        VALUES ($1, $2, $3, $4, now() + make_interval(days => $5::INTEGER))
+        RETURNING id, expires_at",
+        workspace_id,
+        hash_token(&token),
+        payload.include_chats,
+        user.user_id,
+        payload.expires_in_days.map(|days| days as i32)
+    )
+    .fetch_one(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed.into_response())?;
+    let detail = json!({ "workspace_id": workspace_id, "share_id": created.id, "include_chats": payload.include_chats });
+    audit::record(&db, &client, AuditEvent::ShareCreated, Some(user.user_id), detail).await;
+    Ok((StatusCode::CREATED, Json(CreatedShare {
+        id: created.id,
+        url: format!("{}/share/{}", app_url(), token),
+        token,
+        include_chats: payload.include_chats,
+        expires_at: created.expires_at
+    })))
+}
+
+pub async fn list(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceRead>,
+    Path(workspace_id): Path<i32>
+) -> Result<Json<Vec<ShareSummary>>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested FETCH share links of workspace <{}>", user.user_id, workspace_id));
+    require_workspace_role(workspace_id, user.user_id, WorkspaceRole::Owner, &db).await?;
+    let shares = sqlx::query_as!(
+        ShareSummary,
+        r#"SELECT share_links.id, share_links.include_chats, users.username AS "created_by?",
+            share_links.created, share_links.expires_at
+        FROM share_links
+        LEFT JOIN users ON users.id = share_links.created_by
+        WHERE share_links.workspace_id = $1 AND share_links.revoked_at IS NULL
+            AND (share_links.expires_at IS NULL OR share_links.expires_at > now())
+        ORDER BY share_links.created DESC"#,
+        workspace_id
+    )
+    .fetch_all(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(Json(shares))
+}
+
+pub async fn revoke(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path((workspace_id, id)): Path<(i32, i32)>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested REVOKE share link <{}> of workspace <{}>", user.user_id, id, workspace_id));
+    require_workspace_role(workspace_id, user.user_id, WorkspaceRole::Owner, &db).await?;
+    let result = sqlx::query!(
+        "UPDATE share_links SET revoked_at = now()
+        WHERE id = $1 AND workspace_id = $2 AND revoked_at IS NULL",
+        id,
+        workspace_id
+    )
+    .execute(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::ItemNotFound);
+    }
+    let detail = json!({ "workspace_id": workspace_id, "share_id": id });
+    audit::record(&db, &client, AuditEvent::ShareRevoked, Some(user.user_id), detail).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Unauthenticated, the token is the only credential
+pub async fn view(
+    State(db): State<Pool<Postgres>>,
+    Path(token): Path<String>
+) -> Result<Json<SharedWorkspace>, ApiError> {
+    let share = sqlx::query!(
+        "SELECT share_links.id, share_links.workspace_id, share_links.include_chats,
+            workspaces.title, workspaces.description, workspaces.root_id
+        FROM share_links
+        JOIN workspaces ON workspaces.id = share_links.workspace_id
+        WHERE share_links.token_hash = $1 AND share_links.revoked_at IS NULL AND workspaces.deleted_at IS NULL
+            AND (share_links.expires_at IS NULL OR share_links.expires_at > now())",
+        hash_token(&token)
+    )
+    .fetch_optional(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::ItemNotFound)?;
+    log(HTTP, &format!("Share link <{}> requested GET workspace <{}>", share.id, share.workspace_id));
+
+    let chats = if share.include_chats {
+        let transcripts = workspace_transcripts(share.workspace_id, &db).await?;
+        Some(transcripts
+            .into_iter()
+            .map(|(node_id, messages)| SharedChat { node_id, messages })
+            .collect())
+    } else {
+        None
+    };
+    Ok(Json(SharedWorkspace {
+        title: share.title,
+        description: share.description,
+        root_id: share.root_id,
+        nodes: fetch_nodes(share.workspace_id, &db).await?,
+        chats
+    }))
+}
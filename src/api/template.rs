@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use crate::auth::role::Role;
+use crate::debug::{log, LogType::HTTP};
+use super::access::scope;
+use super::api::ApiError;
+use super::auth_user::AuthUser;
+use super::group::is_group_member;
+use super::member::{require_workspace_role, WorkspaceRole};
+use super::tree::{insert_workspace, Tree};
+use super::validation::{Validate, ValidJson, ValidationErrors, Validator};
+
+/*
+
+    TEMPLATES
+
+    A template is a saved copy of the node DAG of a workspace that new workspaces can be
+    started from. Nodes are saved unresolved. Who sees a template depends on its visibility:
+
+    PRIVATE     -- Only the user who saved it.
+    GROUP       -- Also every member of one group (see group.rs).
+    GLOBAL      -- Everyone. Only teachers and admins can publish these.
+
+    Node names and summaries may contain placeholders written as {{key}}. When a workspace
+    is made from the template every placeholder with a value is replaced, the rest stay.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Private,
+    Group,
+    Global
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Private => "private",
+            Visibility::Group => "group",
+            Visibility::Global => "global"
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateTemplateRequest {
+    workspace_id: i32,
+    name: String,
+    description: Option<String>,
+    visibility: Visibility,
+    // Required for group templates, ignored otherwise
+    group_id: Option<i32>
+}
+
+impl Validate for CreateTemplateRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut validator = Validator::new();
+        validator
+            .required("name", &self.name)
+            .max_length("name", &self.name, 100);
+        if let Some(description) = &self.description {
+            validator.max_length("description", description, 1000);
+        }
+        if self.visibility == Visibility::Group {
+            validator.present("group_id", &self.group_id);
+        }
+        validator.finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FetchTemplatesQuery {
+    visibility: Option<Visibility>,
+    // Matched against the name and description, case-insensitively
+    search: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct InstantiateRequest {
+    // Defaults to the name of the template
+    title: Option<String>,
+    description: Option<String>,
+    #[serde(default)]
+    values: HashMap<String, String>
+}
+
+impl Validate for InstantiateRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut validator = Validator::new();
+        if let Some(title) = &self.title {
+            validator
+                .required("title", title)
+                .max_length("title", title, 100);
+        }
+        if let Some(description) = &self.description {
+            validator.max_length("description", description, 1000);
+        }
+        validator.finish()
+    }
+}
+
+#[derive(Serialize)]
+pub struct TemplateSummary {
+    id: i32,
+    name: String,
+    description: Option<String>,
+    visibility: String,
+    group_id: Option<i32>,
+    owner: Option<String>,
+    nodes: usize,
+    // Keys of the {{key}} placeholders in the tree, to ask the user for values
+    placeholders: Vec<String>,
+    created: DateTime<Utc>
+}
+
+#[derive(Serialize)]
+pub struct TemplateDetail {
+    #[serde(flatten)]
+    summary: TemplateSummary,
+    tree: Tree
+}
+
+#[derive(Serialize)]
+pub struct InstantiateResponse {
+    workspace_id: i32
+}
+
+struct TemplateRow {
+    id: i32,
+    user_id: Option<i32>,
+    name: String,
+    description: Option<String>,
+    visibility: String,
+    group_id: Option<i32>,
+    owner: Option<String>,
+    tree: Value,
+    created: DateTime<Utc>
+}
+
+impl TemplateRow {
+    fn tree(&self) -> Result<Tree, ApiError> {
+        serde_json::from_value(self.tree.clone()).map_err(|_| ApiError::DatabaseOperationFailed)
+    }
+
+    fn summary(self, tree: &Tree) -> TemplateSummary {
+        TemplateSummary {
+            id: self.id,
+            name: self.name,
+            description: self.description,
+            visibility: self.visibility,
+            group_id: self.group_id,
+            owner: self.owner,
+            nodes: tree.nodes.len(),
+            placeholders: placeholders(tree),
+            created: self.created
+        }
+    }
+}
+
+// Replaces every {{key}} for which replace returns a value. Keys are letters, digits and '_',
+// spaces inside the braces are ignored. Anything else between braces is left alone.
+fn replace_placeholders(text: &str, mut replace: impl FnMut(&str) -> Option<String>) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let inner = &rest[start + 2..];
+        let key = inner.find("}}").map(|end| (end, inner[..end].trim()));
+        match key {
+            Some((end, key)) if !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                match replace(key) {
+                    Some(value) => result.push_str(&value),
+                    None => result.push_str(&rest[start..start + end + 4])
+                }
+                rest = &inner[end + 2..];
+            },
+            _ => {
+                result.push_str("{{");
+                rest = inner;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn placeholders(tree: &Tree) -> Vec<String> {
+    let mut keys: Vec<String> = vec![];
+    for node in &tree.nodes {
+        for text in [Some(&node.name), node.summary.as_ref()].into_iter().flatten() {
+            replace_placeholders(text, |key| {
+                keys.push(key.into());
+                None
+            });
+        }
+    }
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+// A template the user may see, strangers get NotFound so private templates stay hidden
+async fn fetch_visible(id: i32, user_id: i32, db: &Pool<Postgres>) -> Result<TemplateRow, ApiError> {
+    sqlx::query_as!(
+        TemplateRow,
+        r#"SELECT templates.id, templates.user_id, templates.name, templates.description, templates.visibility,
+            templates.group_id, users.username AS "owner?", templates.tree, templates.created
+        FROM templates
+        LEFT JOIN users ON users.id = templates.user_id
+        WHERE templates.id = $1 AND (
+            templates.user_id = $2
+            OR templates.visibility = 'global'
+            OR (templates.visibility = 'group' AND EXISTS (
+                SELECT 1 FROM user_group_members
+                WHERE user_group_members.group_id = templates.group_id AND user_group_members.user_id = $2
+            ))
+        )"#,
+        id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::ItemNotFound)
+}
+
+pub async fn create(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    ValidJson(payload): ValidJson<CreateTemplateRequest>
+) -> Result<(StatusCode, Json<TemplateSummary>), ApiError> {
+    log(HTTP, &format!("UserID <{}> requested CREATE template from workspace <{}>", user.user_id, payload.workspace_id));
+    require_workspace_role(payload.workspace_id, user.user_id, WorkspaceRole::Viewer, &db).await?;
+    let group_id = match payload.visibility {
+        Visibility::Private => None,
+        Visibility::Group => {
+            let group_id = payload.group_id.unwrap_or_default();
+            if !is_group_member(group_id, user.user_id, &db).await? {
+                return Err(ApiError::UnauthorizedAccess);
+            }
+            Some(group_id)
+        },
+        Visibility::Global => {
+            if user.role < Role::Teacher {
+                return Err(ApiError::InsufficientRole);
+            }
+            None
+        }
+    };
+
+    let mut conn = db.acquire().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    let mut tree = Tree::load(payload.workspace_id, &mut conn).await?;
+    tree.nodes.iter_mut().for_each(|node| node.resolved = false);
+    let row = sqlx::query_as!(
+        TemplateRow,
+        r#"WITH template AS (
+            INSERT INTO templates (user_id, name, description, visibility, group_id, tree)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *
+        )
+        SELECT template.id AS "id!", template.user_id, template.name AS "name!", template.description,
+            template.visibility AS "visibility!", template.group_id, users.username AS "owner?",
+            template.tree AS "tree!", template.created AS "created!"
+        FROM template
+        LEFT JOIN users ON users.id = template.user_id"#,
+        user.user_id,
+        payload.name.trim(),
+        payload.description,
+        payload.visibility.as_str(),
+        group_id,
+        serde_json::to_value(&tree).map_err(|_| ApiError::DatabaseOperationFailed)?
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok((StatusCode::CREATED, Json(row.summary(&tree))))
+}
+
+// Own, group and global templates, by name
+pub async fn list(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceRead>,
+    Query(query): Query<FetchTemplatesQuery>
+) -> Result<Json<Vec<TemplateSummary>>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested FETCH templates", user.user_id));
+    let rows = sqlx::query_as!(
+        TemplateRow,
+        r#"SELECT templates.id, templates.user_id, templates.name, templates.description, templates.visibility,
+            templates.group_id, users.username AS "owner?", templates.tree, templates.created
+        FROM templates
+        LEFT JOIN users ON users.id = templates.user_id
+        WHERE (
+            templates.user_id = $1
+            OR templates.visibility = 'global'
+            OR (templates.visibility = 'group' AND EXISTS (
+                SELECT 1 FROM user_group_members
+                WHERE user_group_members.group_id = templates.group_id AND user_group_members.user_id = $1
+            ))
+        )
+            AND ($2::TEXT IS NULL OR templates.visibility = $2)
+            AND ($3::TEXT IS NULL OR strpos(lower(templates.name), lower($3)) > 0
+                OR strpos(lower(COALESCE(templates.description, '')), lower($3)) > 0)
+        ORDER BY lower(templates.name), templates.id"#,
+        user.user_id,
+        query.visibility.map(|visibility| visibility.as_str()),
+        query.search.as_deref().map(str::trim).filter(|search| !search.is_empty())
+    )
+    .fetch_all(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+
+    let mut templates = vec![];
+    for row in rows {
+        let tree = row.tree()?;
+        templates.push(row.summary(&tree));
+    }
+    Ok(Json(templates))
+}
+
+pub async fn get(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceRead>,
+    Path(id): Path<i32>
+) -> Result<Json<TemplateDetail>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested GET template <{}>", user.user_id, id));
+    let row = fetch_visible(id, user.user_id, &db).await?;
+    let tree = row.tree()?;
+    Ok(Json(TemplateDetail { summary: row.summary(&tree), tree }))
+}
+
+// Only by whoever saved it, or an admin, who can also delete private and group templates
+// they cannot see
+pub async fn delete(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path(id): Path<i32>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested DELETE template <{}>", user.user_id, id));
+    if user.role < Role::Admin {
+        let row = fetch_visible(id, user.user_id, &db).await?;
+        if row.user_id != Some(user.user_id) {
+            return Err(ApiError::InsufficientRole);
+        }
+    }
+    sqlx::query_scalar!("DELETE FROM templates WHERE id = $1 RETURNING id", id)
+        .fetch_optional(&db)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?
+        .ok_or(ApiError::ItemNotFound)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Makes a new workspace owned by the caller from the template, all or nothing
+pub async fn instantiate(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path(id): Path<i32>,
+    ValidJson(payload): ValidJson<InstantiateRequest>
+) -> Result<(StatusCode, Json<InstantiateResponse>), Response> {
+    log(HTTP, &format!("UserID <{}> requested INSTANTIATE template <{}>", user.user_id, id));
+    let row = fetch_visible(id, user.user_id, &db).await.map_err(IntoResponse::into_response)?;
+    let mut tree = row.tree().map_err(IntoResponse::into_response)?;
+    let substitute = |text: &str| replace_placeholders(text, |key| payload.values.get(key).cloned());
+    for node in tree.nodes.iter_mut() {
+        node.name = substitute(&node.name);
+        node.summary = node.summary.as_deref().map(substitute);
+    }
+    let title = payload.title.clone().unwrap_or_else(|| substitute(&row.name));
+    let description = payload.description.clone().or(row.description.as_deref().map(substitute));
+
+    // Long values can push the texts they are put into past the limits of their fields
+    let name = longest(tree.nodes.iter().map(|node| node.name.as_str()));
+    let summary = longest(tree.nodes.iter().filter_map(|node| node.summary.as_deref()));
+    Validator::new()
+        .max_length("values", name, 100)
+        .max_length("values", summary, 1000)
+        .max_length("values", &title, 100)
+        .max_length("values", description.as_deref().unwrap_or_default(), 1000)
+        .finish()
+        .map_err(IntoResponse::into_response)?;
+
+    let mut tx = db.begin().await.map_err(|_| ApiError::DatabaseOperationFailed.into_response())?;
+    let workspace_id = insert_workspace(user.user_id, &title, description.as_deref(), &mut *tx)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    tree.insert(workspace_id, &mut tx).await.map_err(IntoResponse::into_response)?;
+    tx.commit().await.map_err(|_| ApiError::DatabaseOperationFailed.into_response())?;
+    Ok((StatusCode::CREATED, Json(InstantiateResponse { workspace_id })))
+}
+
+fn longest<'a>(texts: impl Iterator<Item = &'a str>) -> &'a str {
+    texts.max_by_key(|text| text.chars().count()).unwrap_or_default()
+}
@@ -0,0 +1,169 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use dotenvy::dotenv;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use crate::config::env_or;
+use crate::debug::{errlog, log, LogType::{HTTP, JOB}};
+use super::access::scope;
+use super::api::ApiError;
+use super::audit::{self, AuditEvent};
+use super::auth_user::AuthUser;
+use super::client::ClientInfo;
+
+/*
+
+    TRASH
+
+    Deleting a workspace only sets its deleted_at. From then on it is gone for every other
+    endpoint (workspace_role does not see trashed workspaces, so no member can open it, share
+    links stop working and it is left out of the listings) until one of its owners restores it.
+
+    A background job removes workspaces for good once they have been in the trash longer than
+    TRASH_RETENTION_DAYS (30), checking every TRASH_PURGE_INTERVAL_MINUTES (60). Owners can
+    also purge one right away.
+
+*/
+
+struct TrashConfig {
+    retention_days: i32,
+    purge_interval_minutes: u64
+}
+
+static CONFIG: LazyLock<TrashConfig> = LazyLock::new(|| {
+    dotenv().expect("Failed to load environment variables!");
+    TrashConfig {
+        retention_days: env_or("TRASH_RETENTION_DAYS", 30),
+        purge_interval_minutes: env_or("TRASH_PURGE_INTERVAL_MINUTES", 60)
+    }
+});
+
+#[derive(Serialize)]
+pub struct TrashedWorkspace {
+    id: i32,
+    title: String,
+    description: Option<String>,
+    deleted_at: DateTime<Utc>,
+    deleted_by: Option<String>,
+    // When the purge job will remove it
+    purge_at: DateTime<Utc>
+}
+
+// Trashed workspaces the user owns, their members can no longer see them
+pub async fn list(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceRead>
+) -> Result<Json<Vec<TrashedWorkspace>>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested FETCH trash", user.user_id));
+    let workspaces = sqlx::query_as!(
+        TrashedWorkspace,
+        r#"SELECT workspaces.id, workspaces.title, workspaces.description,
+            workspaces.deleted_at AS "deleted_at!", users.username AS "deleted_by?",
+            workspaces.deleted_at + make_interval(days => $2) AS "purge_at!"
+        FROM workspaces
+        JOIN workspace_members ON workspace_members.workspace_id = workspaces.id
+        LEFT JOIN users ON users.id = workspaces.deleted_by
+        WHERE workspace_members.user_id = $1 AND workspace_members.role = 'owner'
+            AND workspace_members.accepted_at IS NOT NULL AND workspaces.deleted_at IS NOT NULL
+        ORDER BY workspaces.deleted_at DESC"#,
+        user.user_id,
+        CONFIG.retention_days
+    )
+    .fetch_all(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(Json(workspaces))
+}
+
+// Returns the title of the trashed workspace. Anything else looks like it does not exist.
+async fn require_trashed_owner(workspace_id: i32, user_id: i32, db: &Pool<Postgres>) -> Result<String, ApiError> {
+    sqlx::query_scalar!(
+        "SELECT workspaces.title FROM workspaces
+        JOIN workspace_members ON workspace_members.workspace_id = workspaces.id
+        WHERE workspaces.id = $1 AND workspaces.deleted_at IS NOT NULL AND workspace_members.user_id = $2
+            AND workspace_members.role = 'owner' AND workspace_members.accepted_at IS NOT NULL",
+        workspace_id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::ItemNotFound)
+}
+
+pub async fn restore(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path(id): Path<i32>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested RESTORE workspace <{}>", user.user_id, id));
+    let title = require_trashed_owner(id, user.user_id, &db).await?;
+    let result = sqlx::query!(
+        "UPDATE workspaces SET deleted_at = NULL, deleted_by = NULL, updated = now() WHERE id = $1 AND deleted_at IS NOT NULL",
+        id
+    )
+    .execute(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::ItemNotFound);
+    }
+    let detail = json!({ "workspace_id": id, "title": title });
+    audit::record(&db, &client, AuditEvent::WorkspaceRestored, Some(user.user_id), detail).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Removes a trashed workspace now instead of waiting for the purge job
+pub async fn purge(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path(id): Path<i32>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested PURGE workspace <{}>", user.user_id, id));
+    let title = require_trashed_owner(id, user.user_id, &db).await?;
+    let result = sqlx::query!("DELETE FROM workspaces WHERE id = $1 AND deleted_at IS NOT NULL", id)
+        .execute(&db)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::ItemNotFound);
+    }
+    let detail = json!({ "workspace_id": id, "title": title });
+    audit::record(&db, &client, AuditEvent::WorkspacePurged, Some(user.user_id), detail).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Runs for the lifetime of the server, a failed purge is logged and tried again next time
+pub fn spawn_purge_job(db: Pool<Postgres>) {
+    let retention_days = CONFIG.retention_days;
+    let period = Duration::from_secs(CONFIG.purge_interval_minutes.max(1) * 60);
+    log(JOB, &format!("Purging workspaces trashed for more than {} days every {:?}", retention_days, period));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let result = sqlx::query!(
+                "DELETE FROM workspaces WHERE deleted_at < now() - make_interval(days => $1)",
+                retention_days
+            )
+            .execute(&db)
+            .await;
+            match result {
+                Ok(result) if result.rows_affected() > 0 => {
+                    log(JOB, &format!("Purged {} workspaces from the trash", result.rows_affected()));
+                },
+                Ok(_) => {},
+                Err(e) => errlog(JOB, &e)
+            }
+        }
+    });
+}
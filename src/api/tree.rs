@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgExecutor, Postgres, Transaction};
+use super::api::ApiError;
+
+/*
+
+    TREE SNAPSHOTS
+
+    A copy of the node DAG of a workspace that can be written into another one. Node ids
+    are only meaningful inside the snapshot: insert() gives every node a new id and returns
+    how the old ids map to the new ones, so callers can carry over anything that points at
+    nodes (chats, the root). Everything runs on the caller's transaction.
+
+*/
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TreeNode {
+    pub id: i32,
+    pub name: String,
+    pub summary: Option<String>,
+    pub optional: bool,
+    pub resolved: bool,
+    pub icon: Option<String>
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Tree {
+    pub nodes: Vec<TreeNode>,
+    // (node, parent) pairs
+    pub edges: Vec<(i32, i32)>,
+    pub root_id: Option<i32>
+}
+
+impl Tree {
+    pub async fn load(workspace_id: i32, conn: &mut PgConnection) -> Result<Tree, ApiError> {
+        let nodes = sqlx::query_as!(
+            TreeNode,
+            "SELECT id, name, summary, optional, resolved, icon FROM nodes WHERE workspace_id = $1 ORDER BY id",
+            workspace_id
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+        let edges = sqlx::query!(
+            "SELECT node_id, parent_id FROM node_parents
+            WHERE node_id IN (SELECT id FROM nodes WHERE workspace_id = $1)
+            ORDER BY node_id, parent_id",
+            workspace_id
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?
+        .into_iter()
+        .map(|edge| (edge.node_id, edge.parent_id))
+        .collect();
+        let root_id = sqlx::query_scalar!("SELECT root_id FROM workspaces WHERE id = $1", workspace_id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(|_| ApiError::DatabaseOperationFailed)?
+            .flatten();
+        Ok(Tree { nodes, edges, root_id })
+    }
+
+    // Adds the nodes to the workspace and makes the snapshot's root its root
+    pub async fn insert(&self, workspace_id: i32, tx: &mut Transaction<'_, Postgres>) -> Result<HashMap<i32, i32>, ApiError> {
+        let mut keys: HashMap<i32, i32> = HashMap::new();
+        for node in &self.nodes {
+            let id = sqlx::query_scalar!(
+                "INSERT INTO nodes (workspace_id, name, summary, optional, resolved, icon)
+                VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+                workspace_id,
+                node.name,
+                node.summary,
+                node.optional,
+                node.resolved,
+                node.icon
+            )
+            .fetch_one(&mut **tx)
+            .await
+            .map_err(|_| ApiError::DatabaseOperationFailed)?;
+            keys.insert(node.id, id);
+        }
+
+        // Edges to nodes outside the snapshot are dropped
+        let (children, parents): (Vec<i32>, Vec<i32>) = self.edges
+            .iter()
+            .filter_map(|(node, parent)| Some((*keys.get(node)?, *keys.get(parent)?)))
+            .unzip();
+        sqlx::query!(
+            "INSERT INTO node_parents (node_id, parent_id) SELECT * FROM UNNEST($1::INTEGER[], $2::INTEGER[])",
+            &children,
+            &parents
+        )
+        .execute(&mut **tx)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+
+        if let Some(root_id) = self.root_id.and_then(|root_id| keys.get(&root_id)) {
+            sqlx::query!("UPDATE workspaces SET root_id = $2 WHERE id = $1", workspace_id, root_id)
+                .execute(&mut **tx)
+                .await
+                .map_err(|_| ApiError::DatabaseOperationFailed)?;
+        }
+        Ok(keys)
+    }
+}
+
+// A new workspace, its creator becomes its first owner
+pub async fn insert_workspace<'e>(
+    user_id: i32,
+    title: &str,
+    description: Option<&str>,
+    executor: impl PgExecutor<'e>
+) -> Result<i32, ApiError> {
+    sqlx::query_scalar!(
+        r#"WITH workspace AS (
+            INSERT INTO workspaces (user_id, title, description) VALUES ($1, $2, $3) RETURNING id, created
+        ), member AS (
+            INSERT INTO workspace_members (workspace_id, user_id, role, accepted_at)
+            SELECT id, $1, 'owner', created FROM workspace
+        )
+        SELECT id AS "id!" FROM workspace"#,
+        user_id,
+        title,
+        description
+    )
+    .fetch_one(executor)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)
+}
@@ -0,0 +1,195 @@
+use std::sync::LazyLock;
+use axum::{
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use dotenvy::dotenv;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use crate::config::env_or;
+use super::access::Access;
+
+#[derive(Serialize)]
+pub struct FieldError {
+    field: &'static str,
+    reason: &'static str,
+    message: String
+}
+
+pub struct ValidationErrors(Vec<FieldError>);
+
+impl IntoResponse for ValidationErrors {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({
+                "error": "ValidationFailed",
+                "fields": self.0
+            }))
+        ).into_response()
+    }
+}
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+// Collects every problem with a payload instead of stopping at the first one
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        Validator::default()
+    }
+
+    fn reject(&mut self, field: &'static str, reason: &'static str, message: String) {
+        self.errors.push(FieldError { field, reason, message });
+    }
+
+    pub fn required(&mut self, field: &'static str, value: &str) -> &mut Validator {
+        if value.trim().is_empty() {
+            self.reject(field, "Required", "must not be empty".into());
+        }
+        self
+    }
+
+    pub fn present<T>(&mut self, field: &'static str, value: &Option<T>) -> &mut Validator {
+        if value.is_none() {
+            self.reject(field, "Required", "must be set".into());
+        }
+        self
+    }
+
+    pub fn max_length(&mut self, field: &'static str, value: &str, max: usize) -> &mut Validator {
+        if value.chars().count() > max {
+            self.reject(field, "TooLong", format!("must be at most {} characters", max));
+        }
+        self
+    }
+
+    pub fn min_length(&mut self, field: &'static str, value: &str, min: usize) -> &mut Validator {
+        if value.chars().count() < min {
+            self.reject(field, "TooShort", format!("must be at least {} characters", min));
+        }
+        self
+    }
+
+    // Letters, digits, '_', '-' and '.' only, so a username can never be mistaken for an email
+    pub fn username(&mut self, field: &'static str, value: &str) -> &mut Validator {
+        self.min_length(field, value, 3).max_length(field, value, 50);
+        if !value.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.') {
+            self.reject(field, "InvalidCharacters", "may only contain letters, digits, '_', '-' and '.'".into());
+        }
+        self
+    }
+
+    pub fn email(&mut self, field: &'static str, value: &str) -> &mut Validator {
+        self.max_length(field, value, 255);
+        if !is_email(value) {
+            self.reject(field, "InvalidEmail", "must be a valid email address".into());
+        }
+        self
+    }
+
+    pub fn password(&mut self, field: &'static str, value: &str) -> &mut Validator {
+        let policy = &*PASSWORD_POLICY;
+        self.min_length(field, value, policy.min_length).max_length(field, value, policy.max_length);
+        if policy.require_letter && !value.chars().any(char::is_alphabetic) {
+            self.reject(field, "MissingLetter", "must contain a letter".into());
+        }
+        if policy.require_digit && !value.chars().any(|c| c.is_ascii_digit()) {
+            self.reject(field, "MissingDigit", "must contain a digit".into());
+        }
+        if policy.require_symbol && value.chars().all(char::is_alphanumeric) {
+            self.reject(field, "MissingSymbol", "must contain a symbol".into());
+        }
+        self
+    }
+
+    pub fn range(&mut self, field: &'static str, value: i64, min: i64, max: i64) -> &mut Validator {
+        if value < min || value > max {
+            self.reject(field, "OutOfRange", format!("must be between {} and {}", min, max));
+        }
+        self
+    }
+
+    // Scopes a personal access token may be granted, account management is never one of them
+    pub fn scopes(&mut self, field: &'static str, values: &[String]) -> &mut Validator {
+        if values.is_empty() {
+            self.reject(field, "Required", "must contain at least one scope".into());
+        }
+        for value in values {
+            if !matches!(Access::parse(value), Some(access) if access != Access::Account) {
+                self.reject(field, "UnknownScope", format!("'{}' is not a valid scope", value));
+            }
+        }
+        self
+    }
+
+    pub fn finish(&mut self) -> Result<(), ValidationErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors(std::mem::take(&mut self.errors)))
+        }
+    }
+}
+
+fn is_email(value: &str) -> bool {
+    let Some((local, domain)) = value.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !domain.contains('@')
+        && !value.chars().any(char::is_whitespace)
+        && domain.split('.').count() >= 2
+        && domain.split('.').all(|label| !label.is_empty())
+}
+
+// Password rules, configurable through the .env
+struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    require_letter: bool,
+    require_digit: bool,
+    require_symbol: bool
+}
+
+static PASSWORD_POLICY: LazyLock<PasswordPolicy> = LazyLock::new(|| {
+    dotenv().expect("Failed to load environment variables!");
+    PasswordPolicy {
+        min_length: env_or("PASSWORD_MIN_LENGTH", 8),
+        max_length: env_or("PASSWORD_MAX_LENGTH", 128),
+        require_letter: env_or("PASSWORD_REQUIRE_LETTER", true),
+        require_digit: env_or("PASSWORD_REQUIRE_DIGIT", true),
+        require_symbol: env_or("PASSWORD_REQUIRE_SYMBOL", false)
+    }
+});
+
+pub fn load_password_policy() {
+    LazyLock::force(&PASSWORD_POLICY);
+}
+
+// Drop-in replacement for Json<T> that also runs the payload's validation rules
+pub struct ValidJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ValidJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(payload) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(|rejection: JsonRejection| rejection.into_response())?;
+        payload.validate().map_err(IntoResponse::into_response)?;
+        Ok(ValidJson(payload))
+    }
+}
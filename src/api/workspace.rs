@@ -1,11 +1,17 @@
 use crate::debug::{log, LogType::HTTP};
-use super::api::{extract_token_data, ApiError};
-use axum::{http::StatusCode, extract::{Path, State}, Json};
-use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
-    TypedHeader,
-};
-use serde::{Deserialize, Serialize};
+use super::access::scope;
+use super::api::ApiError;
+use super::audit::{self, AuditEvent};
+use super::auth_user::AuthUser;
+use super::client::ClientInfo;
+use super::member::{require_workspace_role, WorkspaceRole};
+use super::pagination::{Cursor, Page};
+use super::tree::{insert_workspace, Tree};
+use super::validation::{Validate, ValidJson, ValidationErrors, Validator};
+use axum::{http::StatusCode, extract::{Path, Query, State}, Json};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
 use sqlx::{Pool, Postgres};
 
 // Tree Entity:
@@ -36,7 +42,9 @@ pub struct Workspace {
     id: i32,
     title: String,
     description: Option<String>,
-    root_id: Option<i32>
+    root_id: Option<i32>,
+    created: DateTime<Utc>,
+    updated: DateTime<Utc>
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,6 +53,135 @@ pub struct CreateWorkspaceRequest {
     description: Option<String>,
 }
 
+impl Validate for CreateWorkspaceRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut validator = Validator::new();
+        validator
+            .required("title", &self.title)
+            .max_length("title", &self.title, 100);
+        if let Some(description) = &self.description {
+            validator.max_length("description", description, 1000);
+        }
+        validator.finish()
+    }
+}
+
+#[derive(Serialize)]
+pub struct WorkspaceSummary {
+    id: i32,
+    title: String,
+    description: Option<String>,
+    root_id: Option<i32>,
+    created: DateTime<Utc>,
+    updated: DateTime<Utc>,
+    // The caller's role, workspaces shared with them are listed too
+    role: String,
+    nodes: i64,
+    resolved_nodes: i64,
+    last_chat_activity: Option<NaiveDateTime>,
+    #[serde(skip)]
+    sort_key: String
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceSort {
+    Title,
+    Created,
+    Updated
+}
+
+impl WorkspaceSort {
+    fn as_str(self) -> &'static str {
+        match self {
+            WorkspaceSort::Title => "title",
+            WorkspaceSort::Created => "created",
+            WorkspaceSort::Updated => "updated"
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc
+}
+
+impl SortOrder {
+    fn as_str(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc"
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FetchWorkspacesQuery {
+    sort: Option<WorkspaceSort>,
+    // Titles default to A-Z, dates to newest first
+    order: Option<SortOrder>,
+    // Matched against the title and description, case-insensitively
+    search: Option<String>,
+    limit: Option<i64>,
+    cursor: Option<String>
+}
+
+// Only the fields that are sent change. A null description clears it, a null title is ignored.
+#[derive(Deserialize)]
+pub struct UpdateWorkspaceRequest {
+    title: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    description: Option<Option<String>>,
+}
+
+// Tells a field sent as null (Some(None)) apart from one left out (None)
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl Validate for UpdateWorkspaceRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut validator = Validator::new();
+        if let Some(title) = &self.title {
+            validator
+                .required("title", title)
+                .max_length("title", title, 100);
+        }
+        if let Some(Some(description)) = &self.description {
+            validator.max_length("description", description, 1000);
+        }
+        validator.finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CloneWorkspaceRequest {
+    // Defaults to the title of the original with " (copy)" appended
+    title: Option<String>,
+    #[serde(default)]
+    reset_resolved: bool,
+    #[serde(default)]
+    include_chats: bool
+}
+
+impl Validate for CloneWorkspaceRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut validator = Validator::new();
+        if let Some(title) = &self.title {
+            validator
+                .required("title", title)
+                .max_length("title", title, 100);
+        }
+        validator.finish()
+    }
+}
+
 #[derive(Serialize)]
 pub struct CreateWorkspaceResponse {
     workspace_id: i32,
@@ -52,52 +189,32 @@ pub struct CreateWorkspaceResponse {
 
 pub async fn create_workspace(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
-    Json(payload): Json<CreateWorkspaceRequest>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    ValidJson(payload): ValidJson<CreateWorkspaceRequest>,
 ) -> Result<Json<CreateWorkspaceResponse>, ApiError> {
-    let token_data = extract_token_data(auth)?;
-    log(HTTP, &format!("UserID <{}> requested CREATE workspace", token_data.user_id));
-    let workspace_id = sqlx::query!(
-        "INSERT INTO workspaces (user_id, title, description) VALUES ($1, $2, $3) RETURNING id",
-        token_data.user_id,
-        payload.title,
-        payload.description
-    )
-    .fetch_one(&db)
-    .await
-    .map_err(|_| ApiError::DatabaseOperationFailed)?
-    .id;
+    log(HTTP, &format!("UserID <{}> requested CREATE workspace", user.user_id));
+    let workspace_id = insert_workspace(user.user_id, &payload.title, payload.description.as_deref(), &db).await?;
     Ok(Json(CreateWorkspaceResponse { workspace_id }))
 }
 
 pub async fn get_workspace(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    user: AuthUser<scope::WorkspaceRead>,
     Path(workspace_id): Path<i32>,
 ) -> Result<Json<Vec<WorkspaceNode>>, ApiError> {
-    let token_data = extract_token_data(auth)?;
-    log(HTTP, &format!("UserID <{}> requested GET workspace <{}>", token_data.user_id, workspace_id));
-
-    // Validate that the user owns the workspace
-    let workspace_owner: Option<i32> = sqlx::query_scalar!(
-        "SELECT user_id FROM workspaces WHERE id = $1",
-        workspace_id
-    )
-    .fetch_optional(&db)
-    .await
-    .map_err(|_| ApiError::DatabaseOperationFailed)?;
-
-    println!("ws-owner: {:?}, user: {:?}", workspace_owner, token_data.user_id);
-    if workspace_owner != Some(token_data.user_id) {
-        return Err(ApiError::UnauthorizedAccess);
-    }
+    log(HTTP, &format!("UserID <{}> requested GET workspace <{}>", user.user_id, workspace_id));
+    require_workspace_role(workspace_id, user.user_id, WorkspaceRole::Viewer, &db).await?;
+    Ok(Json(fetch_nodes(workspace_id, &db).await?))
+}
 
+// The tree of a workspace as a flat list, each node listing its parents and branches
+pub async fn fetch_nodes(workspace_id: i32, db: &Pool<Postgres>) -> Result<Vec<WorkspaceNode>, ApiError> {
     // Fetch all nodes in the workspace
     let nodes = sqlx::query!(
         "SELECT id, name, summary, optional, resolved, icon FROM nodes WHERE workspace_id = $1",
         workspace_id
     )
-    .fetch_all(&db)
+    .fetch_all(db)
     .await
     .map_err(|_| ApiError::DatabaseOperationFailed)?;
 
@@ -106,7 +223,7 @@ pub async fn get_workspace(
         "SELECT node_id, parent_id FROM node_parents WHERE node_id IN (SELECT id FROM nodes WHERE workspace_id = $1)",
         workspace_id
     )
-    .fetch_all(&db)
+    .fetch_all(db)
     .await
     .map_err(|_| ApiError::DatabaseOperationFailed)?;
 
@@ -136,43 +253,194 @@ pub async fn get_workspace(
         }
     }
 
-    Ok(Json(node_map.into_values().collect()))
+    Ok(node_map.into_values().collect())
 }
 
+// Keyset pagination: every sort is turned into one text key (dates as fixed width UTC strings,
+// so they compare like the dates themselves) with the id as tie breaker
 pub async fn fetch_workspaces(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
-) -> Result<Json<Vec<Workspace>>, ApiError> {
-    let token_data = extract_token_data(auth)?;
-    log(HTTP, &format!("UserID <{}> requested FETCH workspaces", token_data.user_id));
-    let workspaces = sqlx::query_as!(
-        Workspace,
-        "SELECT id, title, description, root_id FROM workspaces WHERE user_id = $1",
-        token_data.user_id
+    user: AuthUser<scope::WorkspaceRead>,
+    Query(query): Query<FetchWorkspacesQuery>,
+) -> Result<Json<Page<WorkspaceSummary>>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested FETCH workspaces", user.user_id));
+    let sort = query.sort.unwrap_or(WorkspaceSort::Updated);
+    let order = query.order.unwrap_or(match sort {
+        WorkspaceSort::Title => SortOrder::Asc,
+        _ => SortOrder::Desc
+    });
+    // Cursors remember the sort they belong to
+    let sort_name = format!("{}:{}", sort.as_str(), order.as_str());
+    let cursor = query.cursor
+        .as_deref()
+        .map(|cursor| Cursor::decode(cursor, &sort_name))
+        .transpose()?;
+    let limit = query.limit.unwrap_or(50).clamp(1, 100);
+    let search = query.search.as_deref().map(str::trim).filter(|search| !search.is_empty());
+
+    // One extra row tells whether there is a next page
+    let mut workspaces = sqlx::query_as!(
+        WorkspaceSummary,
+        r#"WITH keyed AS (
+            SELECT workspaces.*, workspace_members.role, CASE $2
+                WHEN 'title' THEN lower(workspaces.title)
+                WHEN 'created' THEN to_char(workspaces.created AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS.US')
+                ELSE to_char(workspaces.updated AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS.US')
+            END AS sort_key
+            FROM workspaces
+            JOIN workspace_members ON workspace_members.workspace_id = workspaces.id
+            WHERE workspace_members.user_id = $1 AND workspace_members.accepted_at IS NOT NULL
+                AND workspaces.deleted_at IS NULL
+                AND ($4::TEXT IS NULL OR strpos(lower(title), lower($4)) > 0 OR strpos(lower(COALESCE(description, '')), lower($4)) > 0)
+        )
+        SELECT id, title, description, root_id, created, updated, role, sort_key AS "sort_key!",
+            (SELECT COUNT(*) FROM nodes WHERE nodes.workspace_id = keyed.id) AS "nodes!",
+            (SELECT COUNT(*) FROM nodes WHERE nodes.workspace_id = keyed.id AND nodes.resolved) AS "resolved_nodes!",
+            (SELECT MAX(messages.sent_at) FROM messages
+            JOIN chats ON chats.id = messages.chat_id
+            WHERE chats.workspace_id = keyed.id) AS last_chat_activity
+        FROM keyed
+        WHERE $5::TEXT IS NULL
+            OR ($3 AND (sort_key, id) < ($5, $6))
+            OR (NOT $3 AND (sort_key, id) > ($5, $6))
+        ORDER BY
+            CASE WHEN $3 THEN sort_key END DESC, CASE WHEN $3 THEN id END DESC,
+            CASE WHEN NOT $3 THEN sort_key END ASC, CASE WHEN NOT $3 THEN id END ASC
+        LIMIT $7"#,
+        user.user_id,
+        sort.as_str(),
+        order == SortOrder::Desc,
+        search,
+        cursor.as_ref().map(|cursor| cursor.key.as_str()),
+        cursor.as_ref().map(|cursor| cursor.id),
+        limit + 1
     )
     .fetch_all(&db)
     .await
     .map_err(|_| ApiError::DatabaseOperationFailed)?;
-    Ok(Json(workspaces))
+
+    let next_cursor = if workspaces.len() as i64 > limit {
+        workspaces.truncate(limit as usize);
+        workspaces.last().map(|last| Cursor {
+            sort: sort_name.clone(),
+            key: last.sort_key.clone(),
+            id: last.id
+        }.encode())
+    } else {
+        None
+    };
+    Ok(Json(Page { items: workspaces, next_cursor }))
+}
+
+pub async fn update_workspace(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path(id): Path<i32>,
+    ValidJson(payload): ValidJson<UpdateWorkspaceRequest>,
+) -> Result<Json<Workspace>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested UPDATE workspace <{}>", user.user_id, id));
+    require_workspace_role(id, user.user_id, WorkspaceRole::Editor, &db).await?;
+    let changed = payload.title.is_some() || payload.description.is_some();
+    let workspace = sqlx::query_as!(
+        Workspace,
+        "UPDATE workspaces SET
+            title = COALESCE($2, title),
+            description = CASE WHEN $3 THEN $4 ELSE description END,
+            updated = CASE WHEN $5 THEN now() ELSE updated END
+        WHERE id = $1
+        RETURNING id, title, description, root_id, created, updated",
+        id,
+        payload.title,
+        payload.description.is_some(),
+        payload.description.flatten(),
+        changed
+    )
+    .fetch_optional(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::ItemNotFound)?;
+    Ok(Json(workspace))
 }
 
 pub async fn delete_workspace(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    client: ClientInfo,
+    user: AuthUser<scope::WorkspaceWrite>,
     Path(id): Path<i32>,
 ) -> Result<StatusCode, ApiError> {
-    let token_data = extract_token_data(auth)?;
-    log(HTTP, &format!("UserID <{}> requested DELETE workspace <{}>", token_data.user_id, id));
-    let result = sqlx::query!(
-        "DELETE FROM workspaces WHERE id = $1 AND user_id = $2",
+    log(HTTP, &format!("UserID <{}> requested DELETE workspace <{}>", user.user_id, id));
+    require_workspace_role(id, user.user_id, WorkspaceRole::Owner, &db).await?;
+    // Only moved to the trash, see trash.rs
+    let title = sqlx::query_scalar!(
+        "UPDATE workspaces SET deleted_at = now(), deleted_by = $2 WHERE id = $1 AND deleted_at IS NULL RETURNING title",
         id,
-        token_data.user_id
+        user.user_id
     )
-    .execute(&db)
+    .fetch_optional(&db)
     .await
-    .map_err(|_| ApiError::DatabaseOperationFailed)?;
-    if result.rows_affected() == 0 {
-        return Err(ApiError::ItemNotFound);
-    }
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::ItemNotFound)?;
+    let detail = json!({ "workspace_id": id, "title": title });
+    audit::record(&db, &client, AuditEvent::WorkspaceDeleted, Some(user.user_id), detail).await;
     Ok(StatusCode::NO_CONTENT)
 }
+
+// The default title of a copy, with the source title cut short to keep within 100 characters
+fn copy_title(title: &str) -> String {
+    const SUFFIX: &str = " (copy)";
+    let kept: String = title.chars().take(100 - SUFFIX.len()).collect();
+    format!("{}{}", kept.trim_end(), SUFFIX)
+}
+
+// Copies the workspace into a new one owned by the caller, all or nothing
+pub async fn clone_workspace(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::WorkspaceWrite>,
+    Path(id): Path<i32>,
+    ValidJson(payload): ValidJson<CloneWorkspaceRequest>,
+) -> Result<(StatusCode, Json<CreateWorkspaceResponse>), ApiError> {
+    log(HTTP, &format!("UserID <{}> requested CLONE workspace <{}>", user.user_id, id));
+    require_workspace_role(id, user.user_id, WorkspaceRole::Viewer, &db).await?;
+    let mut tx = db.begin().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    let source = sqlx::query!("SELECT title, description FROM workspaces WHERE id = $1", id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    let title = payload.title.unwrap_or_else(|| copy_title(&source.title));
+    let workspace_id = insert_workspace(user.user_id, &title, source.description.as_deref(), &mut *tx).await?;
+
+    let mut tree = Tree::load(id, &mut tx).await?;
+    if payload.reset_resolved {
+        tree.nodes.iter_mut().for_each(|node| node.resolved = false);
+    }
+    let keys = tree.insert(workspace_id, &mut tx).await?;
+
+    if payload.include_chats {
+        let chats = sqlx::query!("SELECT id, node_id FROM chats WHERE workspace_id = $1", id)
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(|_| ApiError::DatabaseOperationFailed)?;
+        for chat in chats {
+            let chat_id = sqlx::query_scalar!(
+                "INSERT INTO chats (workspace_id, node_id) VALUES ($1, $2) RETURNING id",
+                workspace_id,
+                chat.node_id.and_then(|node_id| keys.get(&node_id).copied())
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|_| ApiError::DatabaseOperationFailed)?;
+            sqlx::query!(
+                "INSERT INTO messages (message, is_user, chat_id, sent_at)
+                SELECT message, is_user, $2, sent_at FROM messages WHERE chat_id = $1 ORDER BY id",
+                chat.id,
+                chat_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| ApiError::DatabaseOperationFailed)?;
+        }
+    }
+
+    tx.commit().await.map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok((StatusCode::CREATED, Json(CreateWorkspaceResponse { workspace_id })))
+}
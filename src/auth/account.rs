@@ -0,0 +1,245 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use crate::api::access::scope;
+use crate::api::audit::{self, AuditEvent};
+use crate::api::auth_user::AuthUser;
+use crate::api::client::ClientInfo;
+use crate::api::validation::{Validate, ValidJson, ValidationErrors, Validator};
+use crate::debug::{log, LogType::HTTP};
+use crate::mail::mailer::{send_mail, Mail};
+use super::auth::{hash_password, normalize_email, verify_password, AuthError};
+use super::limiter::{check_attempts, record_failure, record_success, LimitKey};
+use super::two_factor::checked_second_factor;
+use super::verify::send_verification;
+
+#[derive(Serialize)]
+pub struct Profile {
+    id: i32,
+    username: String,
+    email: String,
+    email_verified: bool,
+    // Address waiting on its verification link after an email change
+    pending_email: Option<String>,
+    two_factor_enabled: bool,
+    // False for accounts created through an identity provider that never set one
+    has_password: bool,
+    created: Option<DateTime<Utc>>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String
+}
+
+impl Validate for ChangePasswordRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .required("current_password", &self.current_password)
+            .password("new_password", &self.new_password)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangeEmailRequest {
+    // Left out by accounts without a password
+    #[serde(default)]
+    password: String,
+    code: Option<String>,
+    new_email: String
+}
+
+impl Validate for ChangeEmailRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .email("new_email", &self.new_email)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteAccountRequest {
+    #[serde(default)]
+    password: String,
+    code: Option<String>
+}
+
+// How recent the login of a password-less account has to be to stand in for the password
+const FRESH_LOGIN_MINUTES: i32 = 10;
+
+// Checks the password again and, for the most sensitive changes, the second factor when 2FA is on.
+// Accounts created through an identity provider have no password. They confirm with their second
+// factor when 2FA is on, otherwise with a session that was logged into in the last few minutes.
+pub async fn reauthenticate(
+    user_id: i32,
+    session_id: Option<i32>,
+    password: &str,
+    second_factor: bool,
+    code: Option<&str>,
+    db: &Pool<Postgres>
+) -> Result<(), AuthError> {
+    let user = sqlx::query!("SELECT password, totp_enabled FROM users WHERE id = $1", user_id)
+        .fetch_one(db)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    if user.password.is_empty() {
+        if user.totp_enabled {
+            return checked_second_factor(user_id, code.unwrap_or_default(), true, db).await;
+        }
+        return match fresh_login(session_id, db).await? {
+            true => Ok(()),
+            false => Err(AuthError::ReauthenticationRequired)
+        };
+    }
+    let keys = [LimitKey::reauth(user_id)];
+    check_attempts(&keys, db).await?;
+    if !verify_password(password, &user.password).unwrap_or(false) {
+        record_failure(&keys, db).await?;
+        return Err(AuthError::InvalidCredentials);
+    }
+    record_success(&keys[0], db).await?;
+    if second_factor && user.totp_enabled {
+        checked_second_factor(user_id, code.unwrap_or_default(), true, db).await?;
+    }
+    Ok(())
+}
+
+// Personal access tokens have no session and never count as a fresh login
+async fn fresh_login(session_id: Option<i32>, db: &Pool<Postgres>) -> Result<bool, AuthError> {
+    let Some(session_id) = session_id else {
+        return Ok(false);
+    };
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM sessions
+        WHERE id = $1 AND revoked_at IS NULL AND created > now() - make_interval(mins => $2)) AS "fresh!""#,
+        session_id,
+        FRESH_LOGIN_MINUTES
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)
+}
+
+pub async fn me(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>
+) -> Result<Json<Profile>, Response> {
+    log(HTTP, &format!("UserID <{}> requested GET profile", user.user_id));
+    let profile = sqlx::query_as!(
+        Profile,
+        r#"SELECT id, username, email, email_verified, totp_enabled AS two_factor_enabled,
+            password <> '' AS "has_password!", created,
+            (SELECT email FROM email_verifications
+            WHERE user_id = users.id AND email <> users.email AND used_at IS NULL AND expires_at > now()
+            ORDER BY created DESC LIMIT 1) AS pending_email
+        FROM users WHERE id = $1"#,
+        user.user_id
+    )
+    .fetch_one(&db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    Ok(Json(profile))
+}
+
+pub async fn change_password(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    user: AuthUser<scope::Account>,
+    ValidJson(payload): ValidJson<ChangePasswordRequest>
+) -> Result<StatusCode, Response> {
+    log(HTTP, &format!("UserID <{}> requested CHANGE password", user.user_id));
+    reauthenticate(user.user_id, user.session_id, &payload.current_password, false, None, &db)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let hash = hash_password(&payload.new_password).map_err(IntoResponse::into_response)?;
+
+    // Every other device has to log in again with the new password
+    let mut tx = db.begin().await.map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    sqlx::query!("UPDATE users SET password = $2 WHERE id = $1", user.user_id, hash)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = now()
+        WHERE user_id = $1 AND id IS DISTINCT FROM $2 AND revoked_at IS NULL",
+        user.user_id,
+        user.session_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    tx.commit().await.map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    audit::record(&db, &client, AuditEvent::PasswordChanged, Some(user.user_id), json!({})).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// The new address only replaces the current one once its verification link is opened
+pub async fn change_email(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>,
+    ValidJson(payload): ValidJson<ChangeEmailRequest>
+) -> Result<StatusCode, Response> {
+    log(HTTP, &format!("UserID <{}> requested CHANGE email to <{}>", user.user_id, payload.new_email));
+    reauthenticate(user.user_id, user.session_id, &payload.password, true, payload.code.as_deref(), &db)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let new_email = normalize_email(&payload.new_email);
+    let taken = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM users WHERE lower(email) = $1) AS "taken!""#,
+        new_email
+    )
+    .fetch_one(&db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    if taken {
+        return Err(AuthError::EmailAlreadyUsed.into_response());
+    }
+    let account = sqlx::query!("SELECT username, email FROM users WHERE id = $1", user.user_id)
+        .fetch_one(&db)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    send_verification(user.user_id, &account.username, &new_email, &db)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    send_mail(Mail {
+        to: account.email,
+        subject: "Your Stackture email is being changed".into(),
+        body: format!(
+            "Hi {},\n\nSomeone asked to change the email of your Stackture account to {}. \
+            It will switch over once the new address is verified.\n\n\
+            If this wasn't you, reset your password right away.",
+            account.username, new_email
+        )
+    });
+    Ok(StatusCode::ACCEPTED)
+}
+
+// Everything the user owns goes with them through ON DELETE CASCADE, except their audit events
+pub async fn delete_account(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    user: AuthUser<scope::Account>,
+    Json(payload): Json<DeleteAccountRequest>
+) -> Result<StatusCode, Response> {
+    log(HTTP, &format!("UserID <{}> requested DELETE account", user.user_id));
+    reauthenticate(user.user_id, user.session_id, &payload.password, true, payload.code.as_deref(), &db)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let username = sqlx::query_scalar!("DELETE FROM users WHERE id = $1 RETURNING username", user.user_id)
+        .fetch_one(&db)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    // The account is gone, so the event is kept under no user and names it in its detail
+    let detail = json!({ "user_id": user.user_id, "username": username });
+    audit::record(&db, &client, AuditEvent::AccountDeleted, None, detail).await;
+    Ok(StatusCode::NO_CONTENT)
+}
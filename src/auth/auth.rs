@@ -1,27 +1,53 @@
 use argon2::password_hash::rand_core::OsRng;
-use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordVerifier, PasswordHasher};
+use argon2::{password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordVerifier, PasswordHasher, Version};
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
 };
 use dotenvy::dotenv;
-use jsonwebtoken::{encode, errors::Error as JWTError, EncodingKey, Header};
-use serde::Serialize;
-use std::env;
+use jsonwebtoken::errors::Error as JWTError;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
 use std::time::{SystemTime, UNIX_EPOCH};
+use crate::config::env_or;
+use super::keys;
+use super::role::Role;
+
+// Audience of access tokens, other services verifying our tokens should require it
+pub const ACCESS_AUDIENCE: &str = "stackture";
+const CHALLENGE_AUDIENCE: &str = "stackture:2fa";
 
 #[derive(Serialize)]
 pub struct Claims {
     sub: i32,
+    sid: i32,
+    // Informational for clients and other services, the server checks the current role on every request
+    role: Role,
+    aud: &'static str,
     exp: usize,
 }
 
+#[derive(Debug)]
 pub enum AuthError {
     InvalidCredentials,
     TokenCreationFailed,
     PasswordHashFailed,
     UserAlreadyExists,
     EmailAlreadyUsed,
+    InvalidRefreshToken,
+    InvalidResetToken,
+    InvalidVerificationToken,
+    TooManyAttempts(u64), // seconds until the lockout ends
+    InvalidChallenge,
+    InvalidTwoFactorCode,
+    TwoFactorAlreadyEnabled,
+    TwoFactorNotEnrolled,
+    UnknownProvider,
+    InvalidOidcState,
+    IdentityProviderFailed,
+    MissingEmailClaim,
+    AccountDisabled,
+    ReauthenticationRequired, // password-less account without a recent login
     DatabaseOperationFailed
 }
 
@@ -43,6 +69,52 @@ impl IntoResponse for AuthError {
             AuthError::EmailAlreadyUsed => {
                 (StatusCode::CONFLICT, "EmailAlreadyUsed").into_response()
             },
+            AuthError::InvalidRefreshToken => {
+                (StatusCode::UNAUTHORIZED, "InvalidRefreshToken").into_response()
+            },
+            AuthError::InvalidResetToken => {
+                (StatusCode::BAD_REQUEST, "InvalidResetToken").into_response()
+            },
+            AuthError::InvalidVerificationToken => {
+                (StatusCode::BAD_REQUEST, "InvalidVerificationToken").into_response()
+            },
+            AuthError::TooManyAttempts(retry_after) => {
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    "TooManyAttempts"
+                ).into_response()
+            },
+            AuthError::InvalidChallenge => {
+                (StatusCode::UNAUTHORIZED, "InvalidChallenge").into_response()
+            },
+            AuthError::InvalidTwoFactorCode => {
+                (StatusCode::UNAUTHORIZED, "InvalidTwoFactorCode").into_response()
+            },
+            AuthError::TwoFactorAlreadyEnabled => {
+                (StatusCode::CONFLICT, "TwoFactorAlreadyEnabled").into_response()
+            },
+            AuthError::TwoFactorNotEnrolled => {
+                (StatusCode::BAD_REQUEST, "TwoFactorNotEnrolled").into_response()
+            },
+            AuthError::UnknownProvider => {
+                (StatusCode::NOT_FOUND, "UnknownProvider").into_response()
+            },
+            AuthError::InvalidOidcState => {
+                (StatusCode::BAD_REQUEST, "InvalidOidcState").into_response()
+            },
+            AuthError::IdentityProviderFailed => {
+                (StatusCode::BAD_GATEWAY, "IdentityProviderFailed").into_response()
+            },
+            AuthError::MissingEmailClaim => {
+                (StatusCode::BAD_REQUEST, "MissingEmailClaim").into_response()
+            },
+            AuthError::AccountDisabled => {
+                (StatusCode::FORBIDDEN, "AccountDisabled").into_response()
+            },
+            AuthError::ReauthenticationRequired => {
+                (StatusCode::UNAUTHORIZED, "ReauthenticationRequired").into_response()
+            },
             AuthError::DatabaseOperationFailed => {
                 (StatusCode::INTERNAL_SERVER_ERROR, "DatabaseOperationFailed").into_response()
             }
@@ -50,41 +122,132 @@ impl IntoResponse for AuthError {
     }
 }
 
-pub fn create_jwt(user_id: i32) -> Result<String, JWTError> {
-    dotenv().expect("Failed to load environment variables!");
-    let expiration = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs()
-        + 7200;
+pub fn create_jwt(user_id: i32, session_id: i32, role: Role) -> Result<String, JWTError> {
     let claims = Claims {
         sub: user_id,
-        exp: expiration as usize,
+        sid: session_id,
+        role,
+        aud: ACCESS_AUDIENCE,
+        exp: expires_in(7200),
+    };
+    keys::sign(&claims)
+}
+
+// Short-lived proof that a two-factor login got past the password step.
+// It carries no session and its own audience, so it is never accepted as an access token.
+#[derive(Serialize, Deserialize)]
+struct ChallengeClaims {
+    sub: i32,
+    purpose: String,
+    aud: String,
+    exp: usize,
+}
+
+const CHALLENGE_PURPOSE: &str = "2fa";
+
+pub fn create_challenge_jwt(user_id: i32) -> Result<String, JWTError> {
+    let claims = ChallengeClaims {
+        sub: user_id,
+        purpose: CHALLENGE_PURPOSE.into(),
+        aud: CHALLENGE_AUDIENCE.into(),
+        exp: expires_in(300),
     };
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(
-            env::var("JWT_SECRET")
-                .expect("JWT_SECRET must be set in the .env!")
-                .as_bytes()
-            )
+    keys::sign(&claims)
+}
+
+// Returns the user the challenge was issued to
+pub fn decode_challenge_jwt(token: &str) -> Result<i32, AuthError> {
+    let claims = keys::verify::<ChallengeClaims>(token, CHALLENGE_AUDIENCE)
+        .map_err(|_| AuthError::InvalidChallenge)?;
+    if claims.purpose != CHALLENGE_PURPOSE {
+        return Err(AuthError::InvalidChallenge);
+    }
+    Ok(claims.sub)
+}
+
+fn expires_in(seconds: u64) -> usize {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (now + seconds) as usize
+}
+
+// Emails are stored lowercase. Usernames keep the casing they were registered with,
+// but both are matched case-insensitively (see the lower() unique indexes on users)
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+// Maps a unique index violation on users to the identity that collided
+pub fn identity_conflict(error: &sqlx::Error) -> Option<AuthError> {
+    match error.as_database_error()?.constraint()? {
+        "users_username_lower_key" => Some(AuthError::UserAlreadyExists),
+        "users_email_lower_key" => Some(AuthError::EmailAlreadyUsed),
+        _ => None
+    }
+}
+
+// Cost of new password hashes, configurable through the .env (defaults are the OWASP minimum).
+// Existing hashes keep the parameters they were made with until needs_rehash upgrades them.
+static ARGON2_PARAMS: LazyLock<Params> = LazyLock::new(|| {
+    dotenv().expect("Failed to load environment variables!");
+    Params::new(
+        env_or("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST),
+        env_or("ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+        env_or("ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+        None
     )
+    .expect("ARGON2_MEMORY_KIB, ARGON2_ITERATIONS or ARGON2_PARALLELISM is out of range in the .env!")
+});
+
+// Checked on logins that have no hash to check, unknown accounts and those without a password,
+// so they take as long as a wrong password and the timing does not tell which accounts exist
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    hash_password("not the password of anyone").expect("Failed to hash the dummy password!")
+});
+
+pub fn load_hash_params() {
+    LazyLock::force(&ARGON2_PARAMS);
+    LazyLock::force(&DUMMY_HASH);
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, ARGON2_PARAMS.clone())
 }
 
 pub fn hash_password(password: &str) -> Result<String, AuthError> {
     let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
+    let password_hash = argon2()
         .hash_password(password.as_bytes(), &salt)
         .map_err(|_| AuthError::PasswordHashFailed)?
         .to_string();
     Ok(password_hash)
 }
 
+// Verifies with whatever algorithm, version and parameters the hash itself records
 pub fn verify_password(password: &str, hashed_password: &str) -> Result<bool, AuthError> {
     let parsed_hash = PasswordHash::new(hashed_password)
         .map_err(|_| AuthError::InvalidCredentials)?;
 
-    Ok(Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok())
+    Ok(argon2().verify_password(password.as_bytes(), &parsed_hash).is_ok())
+}
+
+pub fn verify_dummy_password(password: &str) {
+    let _ = verify_password(password, &DUMMY_HASH);
+}
+
+// True when a hash uses an older algorithm or version, or is cheaper than the current parameters
+pub fn needs_rehash(hashed_password: &str) -> bool {
+    let Ok(parsed_hash) = PasswordHash::new(hashed_password) else {
+        return false;
+    };
+    let Ok(params) = Params::try_from(&parsed_hash) else {
+        return true;
+    };
+    parsed_hash.algorithm != Algorithm::Argon2id.ident()
+        || parsed_hash.version != Some(Version::V0x13.into())
+        || params.m_cost() < ARGON2_PARAMS.m_cost()
+        || params.t_cost() < ARGON2_PARAMS.t_cost()
+        || params.p_cost() < ARGON2_PARAMS.p_cost()
 }
\ No newline at end of file
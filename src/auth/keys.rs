@@ -0,0 +1,175 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::LazyLock;
+use axum::Json;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dotenvy::dotenv;
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    errors::{Error as JWTError, ErrorKind},
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::rsa::{KeyPair as RsaKeyPair, PublicKeyComponents};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::debug::{log, LogType::SETUP};
+
+/*
+
+    JWT SIGNING KEYS
+
+    Tokens are signed with a private key from JWT_KEYS_DIR, one PEM file per key named
+    {kid}.pem (RSA keys sign with RS256, Ed25519 keys with EdDSA). JWT_SIGNING_KID picks the
+    key for new tokens, every other key in the directory is only used to verify. The public
+    halves are served at /.well-known/jwks.json so other services can verify our tokens
+    without being able to mint them.
+
+    Rotating a key:
+
+        1. add the new key file and restart, so every instance already trusts it
+        2. point JWT_SIGNING_KID at it and restart
+        3. remove the old file once the last token it signed has expired (2 hours)
+
+    Without JWT_KEYS_DIR, tokens are signed with the shared HS256 JWT_SECRET as before.
+    When both are set, HS256 tokens are still accepted (not issued) so switching over
+    does not log anyone out. Remove JWT_SECRET afterwards.
+
+*/
+
+struct Key {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Option<Jwk>
+}
+
+struct KeyRing {
+    signing: Key,
+    verifying: Vec<Key>
+}
+
+static KEYS: LazyLock<KeyRing> = LazyLock::new(|| {
+    dotenv().expect("Failed to load environment variables!");
+    let secret = env::var("JWT_SECRET").ok().map(|secret| Key {
+        kid: None,
+        algorithm: Algorithm::HS256,
+        encoding: EncodingKey::from_secret(secret.as_bytes()),
+        decoding: DecodingKey::from_secret(secret.as_bytes()),
+        jwk: None
+    });
+    let Ok(dir) = env::var("JWT_KEYS_DIR") else {
+        return KeyRing {
+            signing: secret.expect("JWT_SECRET or JWT_KEYS_DIR must be set in the .env!"),
+            verifying: vec![]
+        };
+    };
+
+    let mut keys: Vec<Key> = fs::read_dir(&dir)
+        .unwrap_or_else(|_| panic!("JWT_KEYS_DIR <{}> could not be read!", dir))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "pem"))
+        .map(|path| load_key(&path))
+        .collect();
+    let signing_kid = match env::var("JWT_SIGNING_KID") {
+        Ok(kid) => kid,
+        Err(_) if keys.len() == 1 => keys[0].kid.clone().unwrap_or_default(),
+        Err(_) => panic!("JWT_SIGNING_KID must be set in the .env when JWT_KEYS_DIR holds several keys!")
+    };
+    let index = keys.iter()
+        .position(|key| key.kid.as_deref() == Some(signing_kid.as_str()))
+        .unwrap_or_else(|| panic!("No key for JWT_SIGNING_KID <{}> in JWT_KEYS_DIR!", signing_kid));
+    let signing = keys.remove(index);
+    log(SETUP, &format!("Signing tokens with key <{}>, {} more key(s) accepted", signing_kid, keys.len()));
+    keys.extend(secret);
+    KeyRing { signing, verifying: keys }
+});
+
+fn load_key(path: &Path) -> Key {
+    let kid: String = path.file_stem().unwrap_or_default().to_string_lossy().into();
+    let bytes = fs::read(path).unwrap_or_else(|_| panic!("Key file <{}> could not be read!", path.display()));
+    let pem = pem::parse(&bytes).unwrap_or_else(|_| panic!("Key file <{}> is not a PEM file!", path.display()));
+    let common = CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        key_id: Some(kid.clone()),
+        ..Default::default()
+    };
+
+    let rsa = match pem.tag() {
+        "RSA PRIVATE KEY" => RsaKeyPair::from_der(pem.contents()).ok(),
+        _ => RsaKeyPair::from_pkcs8(pem.contents()).ok()
+    };
+    if let Some(pair) = rsa {
+        let public = PublicKeyComponents::<Vec<u8>>::from(pair.public());
+        return Key {
+            kid: Some(kid),
+            algorithm: Algorithm::RS256,
+            encoding: EncodingKey::from_rsa_pem(&bytes).expect("RSA key was parsed above"),
+            decoding: DecodingKey::from_rsa_raw_components(&public.n, &public.e),
+            jwk: Some(Jwk {
+                common: CommonParameters { key_algorithm: Some(KeyAlgorithm::RS256), ..common },
+                algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(&public.n),
+                    e: URL_SAFE_NO_PAD.encode(&public.e)
+                })
+            })
+        };
+    }
+    if let Ok(pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pem.contents()) {
+        let x = URL_SAFE_NO_PAD.encode(pair.public_key().as_ref());
+        return Key {
+            kid: Some(kid),
+            algorithm: Algorithm::EdDSA,
+            encoding: EncodingKey::from_ed_der(pem.contents()),
+            decoding: DecodingKey::from_ed_components(&x).expect("Ed25519 key was parsed above"),
+            jwk: Some(Jwk {
+                common: CommonParameters { key_algorithm: Some(KeyAlgorithm::EdDSA), ..common },
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x
+                })
+            })
+        };
+    }
+    panic!("Key file <{}> is neither an RSA nor an Ed25519 private key!", path.display());
+}
+
+// Loads the keys up front so a broken key setup stops the server at startup, not at the first login
+pub fn load_keys() {
+    LazyLock::force(&KEYS);
+}
+
+pub fn sign<T: Serialize>(claims: &T) -> Result<String, JWTError> {
+    let key = &KEYS.signing;
+    let mut header = Header::new(key.algorithm);
+    header.kid = key.kid.clone();
+    encode(&header, claims, &key.encoding)
+}
+
+// Picks the key named by the token's kid (no kid means the legacy HS256 secret) and only
+// accepts the algorithm that key was made for. The audience is checked when the token has one.
+pub fn verify<T: DeserializeOwned>(token: &str, audience: &str) -> Result<T, JWTError> {
+    let header = decode_header(token)?;
+    let key = std::iter::once(&KEYS.signing)
+        .chain(&KEYS.verifying)
+        .find(|key| key.kid == header.kid && key.algorithm == header.alg)
+        .ok_or(JWTError::from(ErrorKind::InvalidToken))?;
+    let mut validation = Validation::new(key.algorithm);
+    validation.set_audience(&[audience]);
+    Ok(decode::<T>(token, &key.decoding, &validation)?.claims)
+}
+
+pub async fn jwks() -> Json<JwkSet> {
+    let keys = std::iter::once(&KEYS.signing)
+        .chain(&KEYS.verifying)
+        .filter_map(|key| key.jwk.clone())
+        .collect();
+    Json(JwkSet { keys })
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use dotenvy::dotenv;
+use sqlx::{Pool, Postgres};
+use crate::config::env_or;
+use crate::debug::{errlog, log, LogType::JOB};
+use super::auth::AuthError;
+
+/*
+
+    LOGIN RATE LIMITING
+
+    Failed logins are counted per client IP and per account. Once a key runs out of free
+    attempts, every further failure locks it for twice as long as the previous one:
+
+        base, 2 * base, 4 * base, ... capped at max
+
+    A key forgets its failures after a full window without any, or (for accounts) on a
+    successful login. Persisted rows past their window and lockout are deleted periodically. IPs get more free attempts than accounts since a whole school may
+    share one address, and are never cleared by a success so a valid account cannot be
+    used to reset the counter between guesses.
+
+    Password reset requests are counted the same way under their own keys, so nobody can
+    flood an address with reset mails.
+
+    Counters live in process memory, which is enough to reject hammering without touching
+    the database. With LOGIN_LIMITER_PERSIST (the default) they are also kept in the
+    login_attempts table so every instance behind the load balancer sees the same lockouts.
+
+*/
+
+struct LimiterConfig {
+    account_free_attempts: u32,
+    ip_free_attempts: u32,
+    lockout_base: u64,
+    lockout_max: u64,
+    window: u64,
+    persist: bool
+}
+
+static CONFIG: LazyLock<LimiterConfig> = LazyLock::new(|| {
+    dotenv().expect("Failed to load environment variables!");
+    LimiterConfig {
+        account_free_attempts: env_or("LOGIN_ACCOUNT_FREE_ATTEMPTS", 5),
+        ip_free_attempts: env_or("LOGIN_IP_FREE_ATTEMPTS", 20),
+        lockout_base: env_or("LOGIN_LOCKOUT_BASE_SECONDS", 30),
+        lockout_max: env_or("LOGIN_LOCKOUT_MAX_SECONDS", 900),
+        window: env_or("LOGIN_ATTEMPT_WINDOW_SECONDS", 3600),
+        persist: env_or("LOGIN_LIMITER_PERSIST", true)
+    }
+});
+
+pub fn load_config() {
+    LazyLock::force(&CONFIG);
+}
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>
+}
+
+static LOCAL: LazyLock<Mutex<HashMap<String, Attempts>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// One counted identity: an IP address or an account name
+pub struct LimitKey {
+    key: String,
+    free_attempts: u32
+}
+
+impl LimitKey {
+    pub fn ip(ip: IpAddr) -> LimitKey {
+        LimitKey { key: format!("ip:{}", ip), free_attempts: CONFIG.ip_free_attempts }
+    }
+
+    // Keyed by id so the username and the email of an account share one budget
+    pub fn account(user_id: i32) -> LimitKey {
+        LimitKey { key: format!("account:{}", user_id), free_attempts: CONFIG.account_free_attempts }
+    }
+
+    // Identifiers that match no account, counted so probing for them gets locked out the same way
+    pub fn unknown_account(identifier: &str) -> LimitKey {
+        LimitKey { key: format!("unknown:{}", identifier.trim().to_lowercase()), free_attempts: CONFIG.account_free_attempts }
+    }
+
+    pub fn two_factor(user_id: i32) -> LimitKey {
+        LimitKey { key: format!("2fa:{}", user_id), free_attempts: CONFIG.account_free_attempts }
+    }
+
+    // Reset mails asked for from an IP and for an address, whether or not it is registered
+    pub fn reset_ip(ip: IpAddr) -> LimitKey {
+        LimitKey { key: format!("reset-ip:{}", ip), free_attempts: CONFIG.ip_free_attempts }
+    }
+
+    pub fn reset_email(email: &str) -> LimitKey {
+        LimitKey { key: format!("reset:{}", email), free_attempts: CONFIG.account_free_attempts }
+    }
+
+    // Password checks made by an already logged in user, so a stolen token cannot be used to guess it
+    pub fn reauth(user_id: i32) -> LimitKey {
+        LimitKey { key: format!("reauth:{}", user_id), free_attempts: CONFIG.account_free_attempts }
+    }
+}
+
+fn lockout_seconds(failures: u32, free_attempts: u32) -> u64 {
+    if failures <= free_attempts {
+        return 0;
+    }
+    let doublings = (failures - free_attempts - 1).min(32);
+    CONFIG.lockout_base.saturating_mul(1 << doublings).min(CONFIG.lockout_max)
+}
+
+// Fails with TooManyAttempts if any of the keys is currently locked out
+pub async fn check_attempts(keys: &[LimitKey], db: &Pool<Postgres>) -> Result<(), AuthError> {
+    let now = Instant::now();
+    let local_wait = {
+        let local = LOCAL.lock().unwrap();
+        keys.iter()
+            .filter_map(|k| local.get(&k.key)?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| (until - now).as_secs().max(1))
+            .max()
+    };
+    if let Some(wait) = local_wait {
+        return Err(AuthError::TooManyAttempts(wait));
+    }
+    if !CONFIG.persist {
+        return Ok(());
+    }
+
+    // Another instance may have locked the key
+    let names: Vec<String> = keys.iter().map(|k| k.key.clone()).collect();
+    let remote_wait = sqlx::query_scalar!(
+        r#"SELECT CEIL(EXTRACT(EPOCH FROM MAX(locked_until) - now()))::BIGINT AS "wait"
+        FROM login_attempts WHERE key = ANY($1) AND locked_until > now()"#,
+        &names
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    match remote_wait {
+        Some(wait) if wait > 0 => Err(AuthError::TooManyAttempts(wait as u64)),
+        _ => Ok(())
+    }
+}
+
+// Counts a failed login against every key, locking the ones that ran out of attempts
+pub async fn record_failure(keys: &[LimitKey], db: &Pool<Postgres>) -> Result<(), AuthError> {
+    for key in keys {
+        let failures = if CONFIG.persist {
+            let failures = sqlx::query_scalar!(
+                "INSERT INTO login_attempts (key, failures, last_failure) VALUES ($1, 1, now())
+                ON CONFLICT (key) DO UPDATE SET
+                    failures = CASE
+                        WHEN login_attempts.last_failure < now() - make_interval(secs => $2) THEN 1
+                        ELSE login_attempts.failures + 1
+                    END,
+                    last_failure = now()
+                RETURNING failures",
+                key.key,
+                CONFIG.window as f64
+            )
+            .fetch_one(db)
+            .await
+            .map_err(|_| AuthError::DatabaseOperationFailed)?;
+            failures as u32
+        } else {
+            local_failures(&key.key)
+        };
+
+        let lockout = lockout_seconds(failures, key.free_attempts);
+        remember(&key.key, failures, lockout);
+        if CONFIG.persist && lockout > 0 {
+            sqlx::query!(
+                "UPDATE login_attempts SET locked_until = now() + make_interval(secs => $2) WHERE key = $1",
+                key.key,
+                lockout as f64
+            )
+            .execute(db)
+            .await
+            .map_err(|_| AuthError::DatabaseOperationFailed)?;
+        }
+    }
+    Ok(())
+}
+
+fn local_failures(key: &str) -> u32 {
+    let local = LOCAL.lock().unwrap();
+    let window = Duration::from_secs(CONFIG.window);
+    match local.get(key) {
+        Some(attempts) if attempts.last_failure.elapsed() < window => attempts.failures + 1,
+        _ => 1
+    }
+}
+
+fn remember(key: &str, failures: u32, lockout: u64) {
+    let mut local = LOCAL.lock().unwrap();
+    // Keep the map from growing without bound under a spray of distinct keys
+    if local.len() > 10_000 {
+        let window = Duration::from_secs(CONFIG.window);
+        local.retain(|_, a| a.last_failure.elapsed() < window);
+    }
+    local.insert(key.into(), Attempts {
+        failures,
+        last_failure: Instant::now(),
+        locked_until: (lockout > 0).then(|| Instant::now() + Duration::from_secs(lockout))
+    });
+}
+
+// A successful login wipes the slate clean for that key
+pub async fn record_success(key: &LimitKey, db: &Pool<Postgres>) -> Result<(), AuthError> {
+    LOCAL.lock().unwrap().remove(&key.key);
+    if CONFIG.persist {
+        sqlx::query!("DELETE FROM login_attempts WHERE key = $1", key.key)
+            .execute(db)
+            .await
+            .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    }
+    Ok(())
+}
+
+// Removes persisted counters that are past their window and no longer locked, every window
+pub fn spawn_cleanup_job(db: Pool<Postgres>) {
+    if !CONFIG.persist {
+        return;
+    }
+    let window = CONFIG.window;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(window.max(60)));
+        loop {
+            interval.tick().await;
+            let result = sqlx::query!(
+                "DELETE FROM login_attempts
+                WHERE last_failure < now() - make_interval(secs => $1)
+                    AND (locked_until IS NULL OR locked_until < now())",
+                window as f64
+            )
+            .execute(&db)
+            .await;
+            match result {
+                Ok(result) if result.rows_affected() > 0 => {
+                    log(JOB, &format!("Removed {} expired login attempt counters", result.rows_affected()));
+                },
+                Ok(_) => {},
+                Err(e) => errlog(JOB, &e)
+            }
+        }
+    });
+}
@@ -1,40 +1,169 @@
 use axum::{extract::State, Json};
+use crate::api::validation::{Validate, ValidJson, ValidationErrors, Validator};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sqlx::{Pool, Postgres};
-use crate::debug::{log, LogType::HTTP};
-use super::auth::{create_jwt, verify_password, AuthError};
+use crate::api::audit::{self, AuditEvent};
+use crate::api::client::ClientInfo;
+use crate::debug::{errlog, log, LogType::HTTP};
+use super::auth::{create_challenge_jwt, hash_password, needs_rehash, verify_dummy_password, verify_password, AuthError};
+use super::limiter::{check_attempts, record_failure, record_success, LimitKey};
+use super::session::{start_session, SessionTokens};
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginRequest {
-    username: String,
+    // Either the username or the email of the account
+    #[serde(alias = "username")]
+    identifier: String,
     password: String
 }
 
+impl Validate for LoginRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .required("identifier", &self.identifier)
+            .max_length("identifier", &self.identifier, 255)
+            .required("password", &self.password)
+            .finish()
+    }
+}
+
 #[derive(Serialize)]
-pub struct LoginResponse {
-    token: String
+#[serde(untagged)]
+pub enum LoginResponse {
+    Session {
+        token: String,
+        refresh_token: String
+    },
+    // Accounts with 2FA must finish at /auth/2fa/verify
+    Challenge {
+        two_factor_required: bool,
+        challenge_token: String
+    }
 }
 
 pub async fn login(
     State(db): State<Pool<Postgres>>,
-    Json(payload): Json<LoginRequest>
+    client: ClientInfo,
+    ValidJson(payload): ValidJson<LoginRequest>
 ) -> Result<Json<LoginResponse>, AuthError> {
-    log(HTTP, &format!("User <{}> requested LOGIN", payload.username));
+    log(HTTP, &format!("User <{}> requested LOGIN from <{}>", payload.identifier, client.ip));
+    // Usernames cannot contain '@', so anything that does is an email
+    let by_email = payload.identifier.contains('@');
     let user = sqlx::query!(
-        "SELECT id, password FROM users WHERE username = $1",
-        payload.username
+        "SELECT id, password, totp_enabled FROM users
+        WHERE ($2 AND lower(email) = lower($1)) OR (NOT $2 AND lower(username) = lower($1))",
+        payload.identifier.trim(),
+        by_email
     )
     .fetch_optional(&db)
     .await
     .map_err(|_| AuthError::DatabaseOperationFailed)?;
-    let user = user.ok_or(AuthError::InvalidCredentials)?;
-    if verify_password(&payload.password, &user.password)? {
-        let token = create_jwt(user.id)
-            .map_err(|_| AuthError::TokenCreationFailed)?;
-
-        Ok(Json(LoginResponse { token }))
+    let keys = [
+        LimitKey::ip(client.ip),
+        match &user {
+            Some(user) => LimitKey::account(user.id),
+            None => LimitKey::unknown_account(&payload.identifier)
+        }
+    ];
+    // Refuse locked out clients before paying for an Argon2 verification
+    if let Err(e) = check_attempts(&keys, &db).await {
+        if let AuthError::TooManyAttempts(_) = e {
+            let detail = json!({ "method": "password", "identifier": payload.identifier, "reason": "locked_out" });
+            audit::record(&db, &client, AuditEvent::LoginFailed, user.as_ref().map(|user| user.id), detail).await;
+        }
+        return Err(e);
+    }
+    let Some(user) = user else {
+        verify_dummy_password(&payload.password);
+        let detail = json!({ "method": "password", "identifier": payload.identifier, "reason": "unknown_user" });
+        audit::record(&db, &client, AuditEvent::LoginFailed, None, detail).await;
+        record_failure(&keys, &db).await?;
+        return Err(AuthError::InvalidCredentials);
+    };
+    // Accounts created through an identity provider have no password to log in with
+    let verified = if user.password.is_empty() {
+        verify_dummy_password(&payload.password);
+        false
+    } else {
+        verify_password(&payload.password, &user.password)?
+    };
+    if verified {
+        record_success(&keys[1], &db).await?;
+        if needs_rehash(&user.password) {
+            rehash_password(user.id, &payload.password, &user.password, &db).await;
+        }
+        Ok(Json(finish_login(user.id, user.totp_enabled, "password", &client, &db).await?))
     } else {
+        let detail = json!({ "method": "password", "reason": "invalid_password" });
+        audit::record(&db, &client, AuditEvent::LoginFailed, Some(user.id), detail).await;
+        record_failure(&keys, &db).await?;
         Err(AuthError::InvalidCredentials)
     }
 }
 
+// Upgrades a hash made with weaker settings while the plain password is at hand.
+// Best effort: the login goes through even if this fails, the next one will try again.
+async fn rehash_password(user_id: i32, password: &str, old_hash: &str, db: &Pool<Postgres>) {
+    let Ok(hash) = hash_password(password) else {
+        return;
+    };
+    // Compare against the old hash so a password changed in the meantime is not overwritten
+    let result = sqlx::query!(
+        "UPDATE users SET password = $3 WHERE id = $1 AND password = $2",
+        user_id,
+        old_hash,
+        hash
+    )
+    .execute(db)
+    .await;
+    if let Err(e) = result {
+        errlog(HTTP, &e);
+    }
+}
+
+// Last step of any first factor (password or identity provider): a session, or a 2FA challenge
+pub async fn finish_login(
+    user_id: i32,
+    totp_enabled: bool,
+    method: &str,
+    client: &ClientInfo,
+    db: &Pool<Postgres>
+) -> Result<LoginResponse, AuthError> {
+    if totp_enabled {
+        let challenge_token = create_challenge_jwt(user_id)
+            .map_err(|_| AuthError::TokenCreationFailed)?;
+        return Ok(LoginResponse::Challenge {
+            two_factor_required: true,
+            challenge_token
+        });
+    }
+    let session = open_session(user_id, method, client, db).await?;
+
+    Ok(LoginResponse::Session {
+        token: session.token,
+        refresh_token: session.refresh_token
+    })
+}
+
+// Starts the session of a completed login and records it, or why it was refused
+pub async fn open_session(
+    user_id: i32,
+    method: &str,
+    client: &ClientInfo,
+    db: &Pool<Postgres>
+) -> Result<SessionTokens, AuthError> {
+    match start_session(user_id, db).await {
+        Ok(session) => {
+            audit::record(db, client, AuditEvent::LoginSucceeded, Some(user_id), json!({ "method": method })).await;
+            Ok(session)
+        }
+        Err(AuthError::AccountDisabled) => {
+            let detail = json!({ "method": method, "reason": "account_disabled" });
+            audit::record(db, client, AuditEvent::LoginFailed, Some(user_id), detail).await;
+            Err(AuthError::AccountDisabled)
+        }
+        Err(e) => Err(e)
+    }
+}
+
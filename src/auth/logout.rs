@@ -0,0 +1,25 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use crate::api::audit::{self, AuditEvent};
+use crate::api::client::ClientInfo;
+use crate::debug::{log, LogType::HTTP};
+use super::auth::AuthError;
+use super::session::revoke_session;
+
+#[derive(Serialize, Deserialize)]
+pub struct LogoutRequest {
+    refresh_token: String
+}
+
+pub async fn logout(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    Json(payload): Json<LogoutRequest>
+) -> Result<StatusCode, AuthError> {
+    log(HTTP, "Anonymous user requested LOGOUT");
+    let session = revoke_session(&payload.refresh_token, &db).await?;
+    audit::record(&db, &client, AuditEvent::SessionRevoked, Some(session.user_id), json!({ "session_id": session.id })).await;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,3 +1,16 @@
+pub mod account;
+pub mod limiter;
 pub mod login;
 pub mod register;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod keys;
+pub mod refresh;
+pub mod role;
+pub mod logout;
+pub mod oidc;
+pub mod password;
+pub mod personal_token;
+pub mod session;
+pub mod token;
+pub mod two_factor;
+pub mod verify;
\ No newline at end of file
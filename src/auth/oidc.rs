@@ -0,0 +1,867 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dotenvy::dotenv;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::{Connection, Pool, Postgres};
+use crate::config::env_or;
+use crate::debug::{log, LogType::HTTP};
+use crate::mail::mailer::app_url;
+use super::auth::{identity_conflict, normalize_email, AuthError};
+use crate::api::client::ClientInfo;
+use super::login::{finish_login, LoginResponse};
+use super::token::{generate_token, hash_token};
+use super::verify::send_verification;
+
+/*
+
+    OPENID CONNECT LOGIN
+
+    GET /auth/oidc/{provider}           -- Redirects the browser to the identity provider with
+                                           a fresh state, nonce and PKCE challenge. The state
+                                           also goes into an HttpOnly cookie.
+    GET /auth/oidc/{provider}/callback  -- Takes the code and state the provider redirected
+                                           back with, redeems the code and logs the user in.
+
+    The provider redirects to a frontend page (OIDC_{NAME}_REDIRECT_URI), which hands code and
+    state to the callback from the same origin. The callback only accepts a state that matches
+    the cookie, so a callback URL carrying someone else's code and state cannot log the browser
+    into their account.
+
+    The returned user is found in this order:
+
+        1. an identity already linked to the provider's subject
+        2. an account with the same email, when the provider is trusted with emails
+           (OIDC_{NAME}_TRUST_EMAIL) and says the email is verified
+        3. a brand new account, created without a password
+
+    Step 2 is off by default, since any provider can claim any address is verified. Without
+    it an identity whose email already belongs to an account is refused with EmailAlreadyUsed.
+
+    When step 2 lands on an account whose email was never verified here, the provider just
+    proved who owns that address, so the unproven password, sessions and 2FA are dropped.
+    Otherwise whoever registered the address first could keep a way into the account.
+
+    Providers are listed in OIDC_PROVIDERS (comma separated). Each one is read from
+    OIDC_{NAME}_ISSUER, OIDC_{NAME}_CLIENT_ID, and optionally OIDC_{NAME}_CLIENT_SECRET,
+    OIDC_{NAME}_REDIRECT_URI, OIDC_{NAME}_SCOPES and OIDC_{NAME}_TRUST_EMAIL. Endpoints and
+    signing keys come from the issuer's /.well-known/openid-configuration, so any compliant
+    provider works, including a mock one running locally.
+
+*/
+
+// Time allowed between leaving for the provider and coming back
+const STATE_MINUTES: i32 = 10;
+
+const STATE_COOKIE: &str = "oidc_state";
+
+struct Provider {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    scopes: String,
+    // Whether its verified emails may log into an existing account with that address
+    trust_email: bool
+}
+
+static PROVIDERS: LazyLock<HashMap<String, Provider>> = LazyLock::new(|| {
+    dotenv().expect("Failed to load environment variables!");
+    let names = env::var("OIDC_PROVIDERS").unwrap_or_default();
+    names.split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let key = |suffix: &str| format!("OIDC_{}_{}", name.to_uppercase(), suffix);
+            let required = |suffix: &str| env::var(key(suffix))
+                .unwrap_or_else(|_| panic!("{} must be set in the .env!", key(suffix)));
+            let provider = Provider {
+                issuer: required("ISSUER").trim_end_matches('/').into(),
+                client_id: required("CLIENT_ID"),
+                client_secret: env::var(key("CLIENT_SECRET")).ok(),
+                redirect_uri: env::var(key("REDIRECT_URI"))
+                    .unwrap_or(format!("{}/login/oidc/{}", app_url(), name)),
+                scopes: env::var(key("SCOPES")).unwrap_or("openid email profile".into()),
+                trust_email: env_or(&key("TRUST_EMAIL"), false)
+            };
+            (name, provider)
+        })
+        .collect()
+});
+
+pub fn load_providers() {
+    LazyLock::force(&PROVIDERS);
+}
+
+static CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build the OIDC http client!")
+});
+
+#[derive(Deserialize, Clone)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String
+}
+
+// Discovery documents and signing keys rarely change, so both are kept per provider.
+// Keys are fetched again whenever a token is signed with a key id we have not seen.
+static DISCOVERY: LazyLock<Mutex<HashMap<String, Discovery>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+static KEYS: LazyLock<Mutex<HashMap<String, JwkSet>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: String,
+    error: Option<String>
+}
+
+#[derive(Serialize)]
+struct TokenRequest<'a> {
+    grant_type: &'a str,
+    code: &'a str,
+    redirect_uri: &'a str,
+    client_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<&'a str>,
+    code_verifier: &'a str
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    // Some providers send this as the string "true"
+    email_verified: Option<Value>,
+    preferred_username: Option<String>
+}
+
+impl IdTokenClaims {
+    fn email_verified(&self) -> bool {
+        match &self.email_verified {
+            Some(Value::Bool(verified)) => *verified,
+            Some(Value::String(verified)) => verified == "true",
+            _ => false
+        }
+    }
+}
+
+fn provider(name: &str) -> Result<&'static Provider, AuthError> {
+    PROVIDERS.get(name).ok_or(AuthError::UnknownProvider)
+}
+
+async fn discovery(name: &str, provider: &Provider) -> Result<Discovery, AuthError> {
+    if let Some(discovery) = DISCOVERY.lock().unwrap().get(name) {
+        return Ok(discovery.clone());
+    }
+    let discovery: Discovery = CLIENT
+        .get(format!("{}/.well-known/openid-configuration", provider.issuer))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|_| AuthError::IdentityProviderFailed)?
+        .json()
+        .await
+        .map_err(|_| AuthError::IdentityProviderFailed)?;
+    if discovery.issuer.trim_end_matches('/') != provider.issuer {
+        return Err(AuthError::IdentityProviderFailed);
+    }
+    DISCOVERY.lock().unwrap().insert(name.into(), discovery.clone());
+    Ok(discovery)
+}
+
+async fn signing_key(name: &str, jwks_uri: &str, kid: Option<&str>) -> Result<DecodingKey, AuthError> {
+    let find = |keys: &JwkSet| match kid {
+        Some(kid) => keys.find(kid).cloned(),
+        None if keys.keys.len() == 1 => keys.keys.first().cloned(),
+        None => None
+    };
+    let cached = KEYS.lock().unwrap().get(name).and_then(find);
+    let jwk = match cached {
+        Some(jwk) => jwk,
+        None => {
+            let keys: JwkSet = CLIENT
+                .get(jwks_uri)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|_| AuthError::IdentityProviderFailed)?
+                .json()
+                .await
+                .map_err(|_| AuthError::IdentityProviderFailed)?;
+            let jwk = find(&keys);
+            KEYS.lock().unwrap().insert(name.into(), keys);
+            jwk.ok_or(AuthError::IdentityProviderFailed)?
+        }
+    };
+    DecodingKey::from_jwk(&jwk).map_err(|_| AuthError::IdentityProviderFailed)
+}
+
+// Checks the id token's signature against the provider's published keys, then its issuer,
+// audience, expiry and nonce
+async fn verify_id_token(
+    name: &str,
+    provider: &Provider,
+    discovery: &Discovery,
+    id_token: &str,
+    nonce: &str
+) -> Result<IdTokenClaims, AuthError> {
+    let header = decode_header(id_token).map_err(|_| AuthError::IdentityProviderFailed)?;
+    // Only asymmetric signatures, a shared secret would let the client forge its own tokens
+    if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        return Err(AuthError::IdentityProviderFailed);
+    }
+    let key = signing_key(name, &discovery.jwks_uri, header.kid.as_deref()).await?;
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&provider.client_id]);
+    validation.set_issuer(&[&provider.issuer, &discovery.issuer]);
+    let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map_err(|_| AuthError::IdentityProviderFailed)?
+        .claims;
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(AuthError::IdentityProviderFailed);
+    }
+    Ok(claims)
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+fn authorization_url(
+    provider: &Provider,
+    discovery: &Discovery,
+    state: &str,
+    nonce: &str,
+    code_verifier: &str
+) -> Result<Url, AuthError> {
+    Url::parse_with_params(&discovery.authorization_endpoint, &[
+        ("response_type", "code"),
+        ("client_id", provider.client_id.as_str()),
+        ("redirect_uri", provider.redirect_uri.as_str()),
+        ("scope", provider.scopes.as_str()),
+        ("state", state),
+        ("nonce", nonce),
+        ("code_challenge", pkce_challenge(code_verifier).as_str()),
+        ("code_challenge_method", "S256")
+    ])
+    .map_err(|_| AuthError::IdentityProviderFailed)
+}
+
+// Trades the code for the id token, proving with the verifier that we started this login
+async fn redeem_code(provider: &Provider, discovery: &Discovery, code: &str, code_verifier: &str) -> Result<String, AuthError> {
+    let tokens: TokenResponse = CLIENT
+        .post(&discovery.token_endpoint)
+        .form(&TokenRequest {
+            grant_type: "authorization_code",
+            code,
+            redirect_uri: &provider.redirect_uri,
+            client_id: &provider.client_id,
+            client_secret: provider.client_secret.as_deref(),
+            code_verifier
+        })
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|_| AuthError::IdentityProviderFailed)?
+        .json()
+        .await
+        .map_err(|_| AuthError::IdentityProviderFailed)?;
+    Ok(tokens.id_token)
+}
+
+// Only sent back to the callback, and on the top-level redirect from the provider (Lax)
+fn state_cookie(name: &str, provider: &Provider, state: &str, max_age: i32) -> String {
+    let secure = if provider.redirect_uri.starts_with("https://") { "; Secure" } else { "" };
+    format!("{}={}; Path=/auth/oidc/{}; Max-Age={}; HttpOnly; SameSite=Lax{}", STATE_COOKIE, state, name, max_age, secure)
+}
+
+// Whether the browser presenting the state is the one the login was started in
+fn state_bound(headers: &HeaderMap, state: &str) -> bool {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .any(|(key, value)| key == STATE_COOKIE && hash_token(value) == hash_token(state))
+}
+
+// Remembers what the callback needs to finish the login, keyed by the hashed state
+async fn save_state(
+    name: &str,
+    state: &str,
+    nonce: &str,
+    code_verifier: &str,
+    db: &Pool<Postgres>
+) -> Result<(), AuthError> {
+    sqlx::query!("DELETE FROM oidc_states WHERE expires_at < now()")
+        .execute(db)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    sqlx::query!(
+        "INSERT INTO oidc_states (state_hash, provider, code_verifier, nonce, expires_at)
+        VALUES ($1, $2, $3, $4, now() + make_interval(mins => $5))",
+        hash_token(state),
+        name,
+        code_verifier,
+        nonce,
+        STATE_MINUTES
+    )
+    .execute(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    Ok(())
+}
+
+pub async fn authorize(
+    State(db): State<Pool<Postgres>>,
+    Path(name): Path<String>
+) -> Result<Response, AuthError> {
+    log(HTTP, &format!("Anonymous user requested OIDC login with <{}>", name));
+    let provider = provider(&name)?;
+    let discovery = discovery(&name, provider).await?;
+    let state = generate_token();
+    let nonce = generate_token();
+    let code_verifier = generate_token();
+
+    save_state(&name, &state, &nonce, &code_verifier, &db).await?;
+
+    let url = authorization_url(provider, &discovery, &state, &nonce, &code_verifier)?;
+    let cookie = state_cookie(&name, provider, &state, STATE_MINUTES * 60);
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to(url.as_str())).into_response())
+}
+
+pub async fn callback(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Query(query): Query<CallbackQuery>
+) -> Result<Response, AuthError> {
+    log(HTTP, &format!("Anonymous user returned from OIDC login with <{}>", name));
+    let provider = provider(&name)?;
+    let login = complete_login(&name, provider, &client, &headers, query, &db).await?;
+    let cookie = state_cookie(&name, provider, "", 0);
+    Ok(([(header::SET_COOKIE, cookie)], Json(login)).into_response())
+}
+
+async fn complete_login(
+    name: &str,
+    provider: &Provider,
+    client: &ClientInfo,
+    headers: &HeaderMap,
+    query: CallbackQuery,
+    db: &Pool<Postgres>
+) -> Result<LoginResponse, AuthError> {
+    if !state_bound(headers, &query.state) {
+        return Err(AuthError::InvalidOidcState);
+    }
+    // A state is single use, even when the provider reports an error
+    let pending = sqlx::query!(
+        "DELETE FROM oidc_states WHERE state_hash = $1 AND provider = $2 AND expires_at > now()
+        RETURNING code_verifier, nonce",
+        hash_token(&query.state),
+        name
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?
+    .ok_or(AuthError::InvalidOidcState)?;
+    if query.error.is_some() {
+        return Err(AuthError::InvalidCredentials);
+    }
+    let code = query.code.ok_or(AuthError::InvalidOidcState)?;
+
+    let discovery = discovery(name, provider).await?;
+    let id_token = redeem_code(provider, &discovery, &code, &pending.code_verifier).await?;
+    let claims = verify_id_token(name, provider, &discovery, &id_token, &pending.nonce).await?;
+
+    let user_id = resolve_user(name, provider, &claims, db).await?;
+    let totp_enabled = sqlx::query_scalar!("SELECT totp_enabled FROM users WHERE id = $1", user_id)
+        .fetch_one(db)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    finish_login(user_id, totp_enabled, &format!("oidc:{}", name), client, db).await
+}
+
+// Finds or creates the account behind a provider identity (see the order at the top)
+async fn resolve_user(
+    name: &str,
+    provider: &Provider,
+    claims: &IdTokenClaims,
+    db: &Pool<Postgres>
+) -> Result<i32, AuthError> {
+    let linked = sqlx::query_scalar!(
+        "SELECT user_id FROM user_identities WHERE provider = $1 AND subject = $2",
+        name,
+        claims.sub
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    if let Some(user_id) = linked {
+        return Ok(user_id);
+    }
+
+    let email = claims.email.as_deref().map(normalize_email).ok_or(AuthError::MissingEmailClaim)?;
+    // An untrusted provider's word on the email counts for nothing, and its new accounts
+    // verify the address here like a registration
+    let verified = provider.trust_email && claims.email_verified();
+    let existing = if verified {
+        sqlx::query!("SELECT id, email_verified FROM users WHERE lower(email) = $1", email)
+            .fetch_optional(db)
+            .await
+            .map_err(|_| AuthError::DatabaseOperationFailed)?
+    } else {
+        None
+    };
+
+    let created = existing.is_none();
+    let mut tx = db.begin().await.map_err(|_| AuthError::DatabaseOperationFailed)?;
+    let user_id = match existing {
+        Some(user) => {
+            if !user.email_verified {
+                sqlx::query!(
+                    "UPDATE users SET email_verified = true, password = '',
+                    totp_enabled = false, totp_secret = NULL, totp_last_step = NULL
+                    WHERE id = $1",
+                    user.id
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| AuthError::DatabaseOperationFailed)?;
+                sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user.id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+                sqlx::query!(
+                    "UPDATE sessions SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL",
+                    user.id
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| AuthError::DatabaseOperationFailed)?;
+            }
+            user.id
+        }
+        None => create_user(claims, &email, verified, &mut tx).await?
+    };
+    sqlx::query!(
+        "INSERT INTO user_identities (user_id, provider, subject, email) VALUES ($1, $2, $3, $4)",
+        user_id,
+        name,
+        claims.sub,
+        email
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    tx.commit().await.map_err(|_| AuthError::DatabaseOperationFailed)?;
+
+    if created && !verified {
+        let username = sqlx::query_scalar!("SELECT username FROM users WHERE id = $1", user_id)
+            .fetch_one(db)
+            .await
+            .map_err(|_| AuthError::DatabaseOperationFailed)?;
+        send_verification(user_id, &username, &email, db)
+            .await
+            .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    }
+    Ok(user_id)
+}
+
+// Accounts made through a provider have an empty password, which no Argon2 hash ever matches,
+// so they can only log in through the provider until a password is set with a reset
+async fn create_user(
+    claims: &IdTokenClaims,
+    email: &str,
+    verified: bool,
+    tx: &mut sqlx::Transaction<'_, Postgres>
+) -> Result<i32, AuthError> {
+    let base = username_candidate(claims, email);
+    for attempt in 0..5 {
+        let username = if attempt == 0 {
+            base.clone()
+        } else {
+            format!("{}{}", base, &hash_token(&generate_token())[..4])
+        };
+        // A savepoint keeps the transaction usable after a username collision
+        let mut savepoint = Connection::begin(&mut **tx).await.map_err(|_| AuthError::DatabaseOperationFailed)?;
+        let inserted = sqlx::query_scalar!(
+            "INSERT INTO users (username, email, password, email_verified) VALUES ($1, $2, '', $3) RETURNING id",
+            username,
+            email,
+            verified
+        )
+        .fetch_one(&mut *savepoint)
+        .await;
+        match inserted {
+            Ok(user_id) => {
+                savepoint.commit().await.map_err(|_| AuthError::DatabaseOperationFailed)?;
+                return Ok(user_id);
+            }
+            Err(e) => match identity_conflict(&e) {
+                Some(AuthError::UserAlreadyExists) => continue,
+                Some(conflict) => return Err(conflict),
+                None => return Err(AuthError::DatabaseOperationFailed)
+            }
+        }
+    }
+    Err(AuthError::UserAlreadyExists)
+}
+
+// Follows the same rules as registration usernames: 3 to 50 letters, digits, '_', '-' or '.'
+fn username_candidate(claims: &IdTokenClaims, email: &str) -> String {
+    let source = claims.preferred_username.as_deref()
+        .filter(|name| !name.contains('@'))
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+    let username: String = source.chars()
+        .filter(|c| c.is_alphanumeric() || *c == '_' || *c == '-' || *c == '.')
+        .take(40)
+        .collect();
+    if username.chars().count() < 3 {
+        format!("user{}", username)
+    } else {
+        username
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::sync::{Arc, Mutex};
+    use axum::{
+        extract::{Form, Query, State},
+        http::{header, HeaderMap, HeaderValue, StatusCode},
+        response::{IntoResponse, Redirect, Response},
+        routing::{get, post},
+        Json, Router,
+    };
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use reqwest::{redirect::Policy, Client, Url};
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use super::*;
+
+    // A local identity provider: logs in whoever asks and follows the spec for state, nonce and PKCE
+    #[derive(Clone)]
+    struct MockIdp {
+        issuer: String,
+        pkcs8: Arc<Vec<u8>>,
+        // Who it says is logging in
+        subject: String,
+        email: String,
+        // code -> (PKCE challenge, nonce)
+        codes: Arc<Mutex<HashMap<String, (String, String)>>>
+    }
+
+    #[derive(Deserialize)]
+    struct AuthorizeQuery {
+        redirect_uri: String,
+        state: String,
+        nonce: String,
+        code_challenge: String,
+        code_challenge_method: String
+    }
+
+    #[derive(Deserialize)]
+    struct TokenForm {
+        code: String,
+        client_id: String,
+        code_verifier: String
+    }
+
+    async fn mock_discovery(State(idp): State<MockIdp>) -> Json<Value> {
+        Json(json!({
+            "issuer": idp.issuer,
+            "authorization_endpoint": format!("{}/authorize", idp.issuer),
+            "token_endpoint": format!("{}/token", idp.issuer),
+            "jwks_uri": format!("{}/jwks", idp.issuer)
+        }))
+    }
+
+    async fn mock_authorize(State(idp): State<MockIdp>, Query(query): Query<AuthorizeQuery>) -> Response {
+        if query.code_challenge_method != "S256" {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        let code = generate_token();
+        idp.codes.lock().unwrap().insert(code.clone(), (query.code_challenge, query.nonce));
+        let url = Url::parse_with_params(&query.redirect_uri, &[("code", code.as_str()), ("state", query.state.as_str())]).unwrap();
+        Redirect::to(url.as_str()).into_response()
+    }
+
+    async fn mock_token(State(idp): State<MockIdp>, Form(form): Form<TokenForm>) -> Response {
+        let Some((challenge, nonce)) = idp.codes.lock().unwrap().get(&form.code).cloned() else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        if URL_SAFE_NO_PAD.encode(Sha256::digest(form.code_verifier.as_bytes())) != challenge {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        idp.codes.lock().unwrap().remove(&form.code);
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some("mock".into());
+        let claims = json!({
+            "iss": idp.issuer,
+            "aud": form.client_id,
+            "sub": idp.subject,
+            "exp": 4_102_444_800u64,
+            "nonce": nonce,
+            "email": idp.email,
+            "email_verified": true
+        });
+        let id_token = encode(&header, &claims, &EncodingKey::from_ed_der(&idp.pkcs8)).unwrap();
+        Json(json!({ "id_token": id_token, "token_type": "Bearer" })).into_response()
+    }
+
+    async fn mock_jwks(State(idp): State<MockIdp>) -> Json<Value> {
+        let key = Ed25519KeyPair::from_pkcs8(&idp.pkcs8).unwrap();
+        Json(json!({
+            "keys": [{
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "alg": "EdDSA",
+                "use": "sig",
+                "kid": "mock",
+                "x": URL_SAFE_NO_PAD.encode(key.public_key().as_ref())
+            }]
+        }))
+    }
+
+    async fn start_mock() -> Provider {
+        start_mock_as("mock-user", "mock@example.com").await
+    }
+
+    // Starts the mock on a free port, returns the provider pointing at it
+    async fn start_mock_as(subject: &str, email: &str) -> Provider {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let idp = MockIdp {
+            issuer: issuer.clone(),
+            pkcs8: Arc::new(pkcs8.as_ref().to_vec()),
+            subject: subject.into(),
+            email: email.into(),
+            codes: Arc::new(Mutex::new(HashMap::new()))
+        };
+        let router = Router::new()
+            .route("/.well-known/openid-configuration", get(mock_discovery))
+            .route("/authorize", get(mock_authorize))
+            .route("/token", post(mock_token))
+            .route("/jwks", get(mock_jwks))
+            .with_state(idp);
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        Provider {
+            issuer,
+            client_id: "stackture".into(),
+            client_secret: None,
+            redirect_uri: "http://localhost:3000/login/oidc/mock".into(),
+            scopes: "openid email".into(),
+            trust_email: false
+        }
+    }
+
+    struct Login {
+        state: String,
+        nonce: String,
+        code_verifier: String,
+        // What the provider redirected back with
+        code: String,
+        returned_state: String
+    }
+
+    // Goes to the provider like the browser would and stops at the redirect back
+    async fn log_in_at_provider(provider: &Provider, discovery: &Discovery) -> Login {
+        let (state, nonce, code_verifier) = (generate_token(), generate_token(), generate_token());
+        let url = authorization_url(provider, discovery, &state, &nonce, &code_verifier).unwrap();
+        let browser = Client::builder().redirect(Policy::none()).build().unwrap();
+        let response = browser.get(url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let location = Url::parse(response.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+        assert!(location.as_str().starts_with(&provider.redirect_uri));
+        let query: HashMap<String, String> = location.query_pairs().into_owned().collect();
+        Login { state, nonce, code_verifier, code: query["code"].clone(), returned_state: query["state"].clone() }
+    }
+
+    fn cookie_headers(cookie: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_str(cookie).unwrap());
+        headers
+    }
+
+    // The database from the .env, like the one the query macros are checked against
+    async fn test_db() -> Pool<Postgres> {
+        dotenv().expect("Failed to load environment variables!");
+        PgPool::connect(&env::var("DATABASE_URL").unwrap()).await.unwrap()
+    }
+
+    // Starts the login like authorize does, goes through the provider and hands its answer to
+    // the callback from a browser holding the state cookie of `browser_state`, or its own
+    async fn sign_in(
+        name: &str,
+        provider: &Provider,
+        browser_state: Option<&str>,
+        db: &Pool<Postgres>
+    ) -> Result<LoginResponse, AuthError> {
+        let discovery = discovery(name, provider).await.unwrap();
+        let login = log_in_at_provider(provider, &discovery).await;
+        save_state(name, &login.state, &login.nonce, &login.code_verifier, db).await.unwrap();
+        let set_cookie = state_cookie(name, provider, browser_state.unwrap_or(&login.state), 600);
+        let headers = cookie_headers(set_cookie.split(';').next().unwrap());
+        let client = ClientInfo { ip: IpAddr::from([127, 0, 0, 1]), user_agent: None };
+        let query = CallbackQuery { code: Some(login.code), state: login.returned_state, error: None };
+        complete_login(name, provider, &client, &headers, query, db).await
+    }
+
+    async fn local_user(username: &str, email: &str, db: &Pool<Postgres>) -> i32 {
+        sqlx::query_scalar!(
+            "INSERT INTO users (username, email, password, email_verified) VALUES ($1, $2, 'unproven', false)
+            RETURNING id",
+            username,
+            email
+        )
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn linked_user(name: &str, subject: &str, db: &Pool<Postgres>) -> Option<i32> {
+        sqlx::query_scalar!("SELECT user_id FROM user_identities WHERE provider = $1 AND subject = $2", name, subject)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn completes_a_login_against_the_mock() {
+        let provider = start_mock().await;
+        let discovery = discovery("mock-flow", &provider).await.unwrap();
+        let login = log_in_at_provider(&provider, &discovery).await;
+        assert_eq!(login.returned_state, login.state);
+
+        let set_cookie = state_cookie("mock-flow", &provider, &login.state, 600);
+        let cookie = set_cookie.split(';').next().unwrap();
+        assert!(state_bound(&cookie_headers(&format!("theme=dark; {}", cookie)), &login.returned_state));
+
+        let id_token = redeem_code(&provider, &discovery, &login.code, &login.code_verifier).await.unwrap();
+        let claims = verify_id_token("mock-flow", &provider, &discovery, &id_token, &login.nonce).await.unwrap();
+        assert_eq!(claims.sub, "mock-user");
+        assert!(claims.email_verified());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_state_from_another_browser() {
+        let provider = start_mock().await;
+        let discovery = discovery("mock-state", &provider).await.unwrap();
+        // The attacker's login, replayed in a browser that started its own
+        let attacker = log_in_at_provider(&provider, &discovery).await;
+        let victim = log_in_at_provider(&provider, &discovery).await;
+
+        let set_cookie = state_cookie("mock-state", &provider, &victim.state, 600);
+        let victim_cookie = cookie_headers(set_cookie.split(';').next().unwrap());
+        assert!(!state_bound(&victim_cookie, &attacker.returned_state));
+        assert!(!state_bound(&HeaderMap::new(), &attacker.returned_state));
+        assert!(set_cookie.contains("HttpOnly") && set_cookie.contains("SameSite=Lax"));
+    }
+
+    #[tokio::test]
+    async fn links_creates_and_refuses_accounts_through_the_callback() {
+        let db = test_db().await;
+        let run = &hash_token(&generate_token())[..8];
+        let taken = format!("oidc-taken-{}@example.com", run);
+        let unproven = format!("oidc-unproven-{}@example.com", run);
+        let fresh = format!("oidc-fresh-{}@example.com", run);
+        let taken_id = local_user(&format!("oidctaken{}", run), &taken, &db).await;
+        let unproven_id = local_user(&format!("oidcunproven{}", run), &unproven, &db).await;
+
+        // An untrusted provider cannot get into an account by claiming its address
+        let untrusted = start_mock_as(&format!("taken-{}", run), &taken).await;
+        let refused = sign_in("mock-untrusted", &untrusted, None, &db).await;
+        assert!(matches!(refused, Err(AuthError::EmailAlreadyUsed)));
+        assert_eq!(linked_user("mock-untrusted", &format!("taken-{}", run), &db).await, None);
+        let password = sqlx::query_scalar!("SELECT password FROM users WHERE id = $1", taken_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(password, "unproven");
+
+        // A trusted one links, and drops what the unverified account's owner never proved
+        let mut trusted = start_mock_as(&format!("unproven-{}", run), &unproven).await;
+        trusted.trust_email = true;
+        assert!(matches!(sign_in("mock-trusted", &trusted, None, &db).await, Ok(LoginResponse::Session { .. })));
+        assert_eq!(linked_user("mock-trusted", &format!("unproven-{}", run), &db).await, Some(unproven_id));
+        let linked = sqlx::query!("SELECT password, email_verified FROM users WHERE id = $1", unproven_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert!(linked.password.is_empty() && linked.email_verified);
+
+        // An unknown address gets a new account, which the next login finds by its identity
+        let mut newcomer = start_mock_as(&format!("fresh-{}", run), &fresh).await;
+        newcomer.trust_email = true;
+        assert!(sign_in("mock-fresh", &newcomer, None, &db).await.is_ok());
+        let created = linked_user("mock-fresh", &format!("fresh-{}", run), &db).await.unwrap();
+        assert!(created != taken_id && created != unproven_id);
+        assert!(sign_in("mock-fresh", &newcomer, None, &db).await.is_ok());
+        assert_eq!(linked_user("mock-fresh", &format!("fresh-{}", run), &db).await, Some(created));
+
+        // A callback in a browser that started another login is turned away
+        let other_browser = generate_token();
+        let mismatched = sign_in("mock-fresh", &newcomer, Some(&other_browser), &db).await;
+        assert!(matches!(mismatched, Err(AuthError::InvalidOidcState)));
+
+        sqlx::query!("DELETE FROM users WHERE id = ANY($1)", &[taken_id, unproven_id, created][..])
+            .execute(&db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_pkce_verifier() {
+        let provider = start_mock().await;
+        let discovery = discovery("mock-pkce", &provider).await.unwrap();
+        let login = log_in_at_provider(&provider, &discovery).await;
+
+        let stolen = redeem_code(&provider, &discovery, &login.code, &generate_token()).await;
+        assert!(matches!(stolen, Err(AuthError::IdentityProviderFailed)));
+        assert!(redeem_code(&provider, &discovery, &login.code, &login.code_verifier).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_an_id_token_with_another_nonce() {
+        let provider = start_mock().await;
+        let discovery = discovery("mock-nonce", &provider).await.unwrap();
+        let login = log_in_at_provider(&provider, &discovery).await;
+
+        let id_token = redeem_code(&provider, &discovery, &login.code, &login.code_verifier).await.unwrap();
+        let replayed = verify_id_token("mock-nonce", &provider, &discovery, &id_token, &generate_token()).await;
+        assert!(matches!(replayed, Err(AuthError::IdentityProviderFailed)));
+    }
+}
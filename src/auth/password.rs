@@ -0,0 +1,147 @@
+use axum::{extract::State, http::StatusCode};
+use crate::api::validation::{Validate, ValidJson, ValidationErrors, Validator};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use crate::api::audit::{self, AuditEvent};
+use crate::api::client::ClientInfo;
+use crate::debug::{log, LogType::HTTP};
+use crate::mail::mailer::{app_url, send_mail, Mail};
+use super::auth::{hash_password, normalize_email, AuthError};
+use super::limiter::{check_attempts, record_failure, LimitKey};
+use super::token::{generate_token, hash_token};
+
+// Reset links are single-use and expire after an hour
+const RESET_TOKEN_MINUTES: i32 = 60;
+
+#[derive(Serialize, Deserialize)]
+pub struct ForgotPasswordRequest {
+    email: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResetPasswordRequest {
+    token: String,
+    password: String
+}
+
+impl Validate for ForgotPasswordRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .email("email", &self.email)
+            .finish()
+    }
+}
+
+impl Validate for ResetPasswordRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .required("token", &self.token)
+            .password("password", &self.password)
+            .finish()
+    }
+}
+
+// Always answers 202 so the endpoint cannot be used to probe which emails are registered.
+// Every request counts against the login limiter under its own keys, once an IP or an address
+// runs out no more mails are sent until the lockout ends.
+pub async fn forgot_password(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    ValidJson(payload): ValidJson<ForgotPasswordRequest>
+) -> Result<StatusCode, AuthError> {
+    log(HTTP, &format!("Anonymous user requested PASSWORD RESET for <{}>", payload.email));
+    let email = normalize_email(&payload.email);
+    let keys = [LimitKey::reset_ip(client.ip), LimitKey::reset_email(&email)];
+    match check_attempts(&keys, &db).await {
+        Err(AuthError::TooManyAttempts(_)) => {
+            log(HTTP, &format!("PASSWORD RESET for <{}> throttled", email));
+            return Ok(StatusCode::ACCEPTED);
+        },
+        result => result?
+    }
+    record_failure(&keys, &db).await?;
+    let user = sqlx::query!(
+        "SELECT id, username, email FROM users WHERE lower(email) = $1",
+        email
+    )
+    .fetch_optional(&db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    let Some(user) = user else {
+        return Ok(StatusCode::ACCEPTED);
+    };
+    let token = generate_token();
+    sqlx::query!(
+        "INSERT INTO password_resets (user_id, token_hash, expires_at)
+        VALUES ($1, $2, now() + make_interval(mins => $3))",
+        user.id,
+        hash_token(&token),
+        RESET_TOKEN_MINUTES
+    )
+    .execute(&db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    send_mail(Mail {
+        to: user.email,
+        subject: "Reset your Stackture password".into(),
+        body: format!(
+            "Hi {},\n\nSomeone asked to reset the password of your Stackture account. \
+            If it was you, open the link below within {} minutes:\n\n{}/reset-password?token={}\n\n\
+            If it was not you, you can safely ignore this mail.",
+            user.username, RESET_TOKEN_MINUTES, app_url(), token
+        )
+    });
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn reset_password(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    ValidJson(payload): ValidJson<ResetPasswordRequest>
+) -> Result<StatusCode, AuthError> {
+    log(HTTP, "Anonymous user requested PASSWORD RESET confirmation");
+    let hash = hash_password(&payload.password)?;
+    let mut tx = db.begin().await.map_err(|_| AuthError::DatabaseOperationFailed)?;
+
+    // Consume the token in the same statement that validates it, so it cannot be replayed
+    let user_id = sqlx::query_scalar!(
+        "UPDATE password_resets SET used_at = now()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+        RETURNING user_id",
+        hash_token(&payload.token)
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?
+    .ok_or(AuthError::InvalidResetToken)?;
+
+    sqlx::query!(
+        "UPDATE users SET password = $1 WHERE id = $2",
+        hash,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+
+    // Any other outstanding reset links and every open session die with the old password
+    sqlx::query!(
+        "UPDATE password_resets SET used_at = now() WHERE user_id = $1 AND used_at IS NULL",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+
+    tx.commit().await.map_err(|_| AuthError::DatabaseOperationFailed)?;
+    audit::record(&db, &client, AuditEvent::PasswordReset, Some(user_id), json!({})).await;
+    Ok(StatusCode::NO_CONTENT)
+}
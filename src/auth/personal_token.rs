@@ -0,0 +1,186 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use crate::api::access::scope;
+use crate::api::audit::{self, AuditEvent};
+use crate::api::auth_user::AuthUser;
+use crate::api::client::ClientInfo;
+use crate::api::api::ApiError;
+use crate::api::validation::{Validate, ValidJson, ValidationErrors, Validator};
+use crate::debug::{log, LogType::HTTP};
+use super::token::{generate_token, hash_token};
+
+/*
+
+    PERSONAL ACCESS TOKENS
+
+    Long lived bearer tokens for scripts. A token is shown once when it is created and only
+    its hash is kept. It works in place of a JWT on any route whose access is among its
+    scopes (workspace:read, workspace:write, node:write, chat), until it expires (if an
+    expiry was set) or is revoked. Tokens can never manage the account, so a leaked one
+    cannot mint more tokens or change the password.
+
+*/
+
+// Lets a bearer token be told apart from a JWT without touching the database
+const TOKEN_PREFIX: &str = "stk_pat_";
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateTokenRequest {
+    name: String,
+    scopes: Vec<String>,
+    expires_in_days: Option<i64>
+}
+
+impl Validate for CreateTokenRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut validator = Validator::new();
+        validator
+            .required("name", &self.name)
+            .max_length("name", &self.name, 100)
+            .scopes("scopes", &self.scopes);
+        if let Some(days) = self.expires_in_days {
+            validator.range("expires_in_days", days, 1, 3650);
+        }
+        validator.finish()
+    }
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    id: i32,
+    name: String,
+    token: String,
+    scopes: Vec<String>,
+    expires_at: Option<DateTime<Utc>>
+}
+
+#[derive(Serialize)]
+pub struct TokenSummary {
+    id: i32,
+    name: String,
+    scopes: Vec<String>,
+    created: DateTime<Utc>,
+    last_used: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>
+}
+
+pub struct PersonalToken {
+    pub user_id: i32,
+    pub scopes: Vec<String>,
+    pub exp: Option<i64>,
+    pub expired: bool,
+    pub revoked: bool,
+    pub email_verified: bool,
+    pub disabled: bool,
+    pub role: String
+}
+
+pub fn is_personal_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+// Looks up the token and its owner, marking it as used
+pub async fn fetch_personal_token(token: &str, db: &Pool<Postgres>) -> Result<Option<PersonalToken>, sqlx::Error> {
+    sqlx::query_as!(
+        PersonalToken,
+        r#"UPDATE personal_access_tokens SET last_used = now()
+        FROM users
+        WHERE personal_access_tokens.token_hash = $1 AND users.id = personal_access_tokens.user_id
+        RETURNING
+            personal_access_tokens.user_id,
+            personal_access_tokens.scopes,
+            EXTRACT(EPOCH FROM personal_access_tokens.expires_at)::BIGINT AS exp,
+            COALESCE(personal_access_tokens.expires_at <= now(), false) AS "expired!",
+            personal_access_tokens.revoked_at IS NOT NULL AS "revoked!",
+            users.email_verified,
+            users.disabled_at IS NOT NULL AS "disabled!",
+            users.role"#,
+        hash_token(token)
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn create(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    user: AuthUser<scope::Account>,
+    ValidJson(payload): ValidJson<CreateTokenRequest>
+) -> Result<(StatusCode, Json<CreatedToken>), Response> {
+    log(HTTP, &format!("UserID <{}> requested CREATE personal access token <{}>", user.user_id, payload.name));
+    let mut scopes = payload.scopes;
+    scopes.sort();
+    scopes.dedup();
+    let token = format!("{}{}", TOKEN_PREFIX, generate_token());
+    let created = sqlx::query!(
+        "INSERT INTO personal_access_tokens (user_id, name, token_hash, scopes, expires_at)
+        VALUES ($1, $2, $3, $4, now() + make_interval(days => $5::INTEGER))
+        RETURNING id, expires_at",
+        user.user_id,
+        payload.name.trim(),
+        hash_token(&token),
+        &scopes,
+        payload.expires_in_days.map(|days| days as i32)
+    )
+    .fetch_one(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed.into_response())?;
+    let detail = json!({ "token_id": created.id, "name": payload.name.trim(), "scopes": scopes });
+    audit::record(&db, &client, AuditEvent::TokenCreated, Some(user.user_id), detail).await;
+    Ok((StatusCode::CREATED, Json(CreatedToken {
+        id: created.id,
+        name: payload.name.trim().into(),
+        token,
+        scopes,
+        expires_at: created.expires_at
+    })))
+}
+
+pub async fn list(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>
+) -> Result<Json<Vec<TokenSummary>>, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested FETCH personal access tokens", user.user_id));
+    let tokens = sqlx::query_as!(
+        TokenSummary,
+        "SELECT id, name, scopes, created, last_used, expires_at FROM personal_access_tokens
+        WHERE user_id = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > now())
+        ORDER BY created DESC",
+        user.user_id
+    )
+    .fetch_all(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(Json(tokens))
+}
+
+pub async fn revoke(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    user: AuthUser<scope::Account>,
+    Path(id): Path<i32>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested REVOKE personal access token <{}>", user.user_id, id));
+    let result = sqlx::query!(
+        "UPDATE personal_access_tokens SET revoked_at = now()
+        WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+        id,
+        user.user_id
+    )
+    .execute(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::ItemNotFound);
+    }
+    audit::record(&db, &client, AuditEvent::TokenRevoked, Some(user.user_id), json!({ "token_id": id })).await;
+    Ok(StatusCode::NO_CONTENT)
+}
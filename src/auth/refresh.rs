@@ -0,0 +1,29 @@
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::debug::{log, LogType::HTTP};
+use super::auth::AuthError;
+use super::session::rotate_session;
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String
+}
+
+#[derive(Serialize)]
+pub struct RefreshResponse {
+    token: String,
+    refresh_token: String
+}
+
+pub async fn refresh(
+    State(db): State<Pool<Postgres>>,
+    Json(payload): Json<RefreshRequest>
+) -> Result<Json<RefreshResponse>, AuthError> {
+    log(HTTP, "Anonymous user requested REFRESH");
+    let session = rotate_session(&payload.refresh_token, &db).await?;
+    Ok(Json(RefreshResponse {
+        token: session.token,
+        refresh_token: session.refresh_token
+    }))
+}
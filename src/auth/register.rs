@@ -1,9 +1,14 @@
 use axum::{extract::State, Json};
+use crate::api::validation::{Validate, ValidJson, ValidationErrors, Validator};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sqlx::{Pool, Postgres};
-use super::auth::hash_password;
+use crate::api::audit::{self, AuditEvent};
+use crate::api::client::ClientInfo;
+use super::auth::{hash_password, identity_conflict, normalize_email};
 use super::auth::AuthError;
-use super::auth::create_jwt;
+use super::session::start_session;
+use super::verify::send_verification;
 use crate::debug::{log, LogType::HTTP};
 
 #[derive(Serialize, Deserialize)]
@@ -13,46 +18,65 @@ pub struct RegisterRequest {
     password: String
 }
 
+impl Validate for RegisterRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .username("username", &self.username)
+            .email("email", &self.email)
+            .password("password", &self.password)
+            .finish()
+    }
+}
+
 #[derive(Serialize)]
 pub struct RegisterResponse {
-    token: String
+    token: String,
+    refresh_token: String
 }
 
 #[axum::debug_handler]
 pub async fn register(
     State(db): State<Pool<Postgres>>,
-    Json(payload): Json<RegisterRequest>
+    client: ClientInfo,
+    ValidJson(payload): ValidJson<RegisterRequest>
 ) -> Result<Json<RegisterResponse>, AuthError> {
     log(HTTP, &format!("Anonymous user requested REGISTER as <{}> with <{}>", payload.username, payload.email));
-    let user = sqlx::query!(
-        "SELECT id, username, email FROM users WHERE username = $1 OR email = $2",
+    let email = normalize_email(&payload.email);
+    let collisions = sqlx::query!(
+        r#"SELECT lower(username) = lower($1) AS "username_taken!", lower(email) = $2 AS "email_taken!"
+        FROM users WHERE lower(username) = lower($1) OR lower(email) = $2"#,
         payload.username,
-        payload.email
+        email
     )
-    .fetch_optional(&db)
+    .fetch_all(&db)
     .await
     .map_err(|_| AuthError::DatabaseOperationFailed)?;
-    if let Some(user) = user {
-        if user.username == payload.username {
-            return Err(AuthError::UserAlreadyExists);
-        }
-        if user.email == payload.email {
-            return Err(AuthError::EmailAlreadyUsed);
-        }
+    if collisions.iter().any(|user| user.username_taken) {
+        return Err(AuthError::UserAlreadyExists);
+    }
+    if collisions.iter().any(|user| user.email_taken) {
+        return Err(AuthError::EmailAlreadyUsed);
     }
     let hash = hash_password(&payload.password)?;
+    // The unique indexes still catch a concurrent registration that slipped past the check above
     let user_id = sqlx::query!(
         "INSERT INTO users (username, email, password) VALUES ($1, $2, $3) RETURNING id",
         payload.username,
-        payload.email,
+        email,
         hash
     )
     .fetch_one(&db)
     .await
-    .map_err(|_| AuthError::TokenCreationFailed)?
+    .map_err(|e| identity_conflict(&e).unwrap_or(AuthError::TokenCreationFailed))?
     .id;
-    let token = create_jwt(user_id)
-        .map_err(|_| AuthError::TokenCreationFailed)?;
+    audit::record(&db, &client, AuditEvent::Registered, Some(user_id), json!({ "username": payload.username })).await;
+    send_verification(user_id, &payload.username, &email, &db)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    let session = start_session(user_id, &db).await?;
 
-    Ok(Json(RegisterResponse { token }))
+    Ok(Json(RegisterResponse {
+        token: session.token,
+        refresh_token: session.refresh_token
+    }))
 }
\ No newline at end of file
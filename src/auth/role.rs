@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+
+// Ordered from least to most privileged, so a check for Teacher also lets admins through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Student,
+    Teacher,
+    Admin
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Student => "student",
+            Role::Teacher => "teacher",
+            Role::Admin => "admin"
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Role> {
+        match value.trim() {
+            "student" => Some(Role::Student),
+            "teacher" => Some(Role::Teacher),
+            "admin" => Some(Role::Admin),
+            _ => None
+        }
+    }
+
+    // For values read back from users.role, which the CHECK constraint keeps valid
+    pub fn from_db(value: &str) -> Role {
+        Role::parse(value).unwrap_or(Role::Student)
+    }
+}
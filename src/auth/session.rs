@@ -0,0 +1,96 @@
+use sqlx::{Pool, Postgres};
+use super::auth::{create_jwt, AuthError};
+use super::role::Role;
+use super::token::{generate_token, hash_token};
+
+// Refresh tokens stay valid for 30 days and are rotated on every use
+const REFRESH_TOKEN_DAYS: i32 = 30;
+
+pub struct SessionTokens {
+    pub token: String,
+    pub refresh_token: String
+}
+
+// Opens a new session for the user and mints its access and refresh tokens.
+// Every way of logging in ends here, so this is where disabled accounts are turned away.
+pub async fn start_session(user_id: i32, db: &Pool<Postgres>) -> Result<SessionTokens, AuthError> {
+    let refresh_token = generate_token();
+    let session = sqlx::query!(
+        r#"WITH account AS (SELECT id, role FROM users WHERE id = $1 AND disabled_at IS NULL)
+        INSERT INTO sessions (user_id, refresh_token_hash, expires_at)
+        SELECT id, $2, now() + make_interval(days => $3) FROM account
+        RETURNING id, (SELECT role FROM account) AS "role!""#,
+        user_id,
+        hash_token(&refresh_token),
+        REFRESH_TOKEN_DAYS
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?
+    .ok_or(AuthError::AccountDisabled)?;
+    let token = create_jwt(user_id, session.id, Role::from_db(&session.role))
+        .map_err(|_| AuthError::TokenCreationFailed)?;
+    Ok(SessionTokens { token, refresh_token })
+}
+
+// Swaps a live refresh token for a new pair, invalidating the presented one
+pub async fn rotate_session(refresh_token: &str, db: &Pool<Postgres>) -> Result<SessionTokens, AuthError> {
+    let next_refresh_token = generate_token();
+    let session = sqlx::query!(
+        "UPDATE sessions
+        SET refresh_token_hash = $2, expires_at = now() + make_interval(days => $3), last_used = now()
+        FROM users
+        WHERE sessions.refresh_token_hash = $1 AND sessions.revoked_at IS NULL AND sessions.expires_at > now()
+            AND users.id = sessions.user_id AND users.disabled_at IS NULL
+        RETURNING sessions.id, sessions.user_id, users.role",
+        hash_token(refresh_token),
+        hash_token(&next_refresh_token),
+        REFRESH_TOKEN_DAYS
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?
+    .ok_or(AuthError::InvalidRefreshToken)?;
+    let token = create_jwt(session.user_id, session.id, Role::from_db(&session.role))
+        .map_err(|_| AuthError::TokenCreationFailed)?;
+    Ok(SessionTokens { token, refresh_token: next_refresh_token })
+}
+
+pub struct RevokedSession {
+    pub id: i32,
+    pub user_id: i32
+}
+
+// Revokes the session owning the refresh token, which also kills its access tokens
+pub async fn revoke_session(refresh_token: &str, db: &Pool<Postgres>) -> Result<RevokedSession, AuthError> {
+    sqlx::query_as!(
+        RevokedSession,
+        "UPDATE sessions SET revoked_at = now() WHERE refresh_token_hash = $1 AND revoked_at IS NULL
+        RETURNING id, user_id",
+        hash_token(refresh_token)
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?
+    .ok_or(AuthError::InvalidRefreshToken)
+}
+
+// The owner of a live session, as far as access checks are concerned
+pub struct SessionUser {
+    pub email_verified: bool,
+    pub role: String
+}
+
+// Looks up the user behind an access token's session, None if the session was revoked
+pub async fn fetch_session_user(session_id: i32, user_id: i32, db: &Pool<Postgres>) -> Result<Option<SessionUser>, sqlx::Error> {
+    sqlx::query_as!(
+        SessionUser,
+        "SELECT users.email_verified, users.role FROM sessions
+        JOIN users ON users.id = sessions.user_id
+        WHERE sessions.id = $1 AND sessions.user_id = $2 AND sessions.revoked_at IS NULL AND users.disabled_at IS NULL",
+        session_id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await
+}
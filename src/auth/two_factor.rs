@@ -0,0 +1,318 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{extract::State, http::StatusCode, response::{IntoResponse, Response}, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::time::{SystemTime, UNIX_EPOCH};
+use totp_rs::{Algorithm, TOTP};
+use crate::api::access::scope;
+use crate::api::audit::{self, AuditEvent};
+use crate::api::auth_user::AuthUser;
+use crate::api::client::ClientInfo;
+use crate::api::validation::{Validate, ValidJson, ValidationErrors, Validator};
+use crate::debug::{log, LogType::HTTP};
+use super::account::reauthenticate;
+use super::auth::{decode_challenge_jwt, AuthError};
+use super::limiter::{check_attempts, record_failure, record_success, LimitKey};
+use super::login::open_session;
+use super::token::hash_token;
+
+/*
+
+    TWO-FACTOR AUTHENTICATION (TOTP)
+
+    ENROLL      -- Stores a new pending secret and hands out its otpauth:// URI.
+    CONFIRM     -- Proves the authenticator app works with a first code, enables 2FA
+                   and returns one-time recovery codes (only ever shown this once).
+    VERIFY      -- Second step of a login: trades the challenge token from /auth/login
+                   plus a code (or a recovery code) for a real session.
+    DISABLE     -- Needs both the password and a code.
+
+    A TOTP code is accepted at most once: the last used time step is stored and
+    anything at or before it is refused.
+
+*/
+
+const ISSUER: &str = "Stackture";
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Serialize)]
+pub struct EnrollResponse {
+    secret: String,
+    otpauth_uri: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConfirmRequest {
+    code: String
+}
+
+#[derive(Serialize)]
+pub struct ConfirmResponse {
+    recovery_codes: Vec<String>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisableRequest {
+    // Left out by accounts without a password
+    #[serde(default)]
+    password: String,
+    code: String
+}
+
+impl Validate for DisableRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        Validator::new()
+            .required("code", &self.code)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VerifyRequest {
+    challenge_token: String,
+    code: String
+}
+
+#[derive(Serialize)]
+pub struct VerifyResponse {
+    token: String,
+    refresh_token: String
+}
+
+fn build_totp(secret: Vec<u8>, username: &str) -> Result<TOTP, AuthError> {
+    TOTP::new(Algorithm::SHA1, 6, 1, 30, secret, Some(ISSUER.into()), username.into())
+        .map_err(|_| AuthError::TwoFactorNotEnrolled)
+}
+
+fn decode_secret(secret: &str) -> Result<Vec<u8>, AuthError> {
+    totp_rs::Secret::Encoded(secret.into())
+        .to_bytes()
+        .map_err(|_| AuthError::TwoFactorNotEnrolled)
+}
+
+// Returns the time step the code belongs to, allowing one step of clock drift either way
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let current = now / totp.step as i64;
+    (current - 1..=current + 1).find(|step| {
+        let expected = totp.generate((step * totp.step as i64) as u64);
+        expected.len() == code.len()
+            && expected.bytes().zip(code.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    })
+}
+
+// Checks a TOTP code against the user's stored secret (pending or enabled) and burns its time step
+async fn verify_totp(user_id: i32, code: &str, db: &Pool<Postgres>) -> Result<bool, AuthError> {
+    let user = sqlx::query!(
+        "SELECT username, totp_secret FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?
+    .ok_or(AuthError::InvalidCredentials)?;
+    let secret = user.totp_secret.ok_or(AuthError::TwoFactorNotEnrolled)?;
+    let totp = build_totp(decode_secret(&secret)?, &user.username)?;
+    let Some(step) = matching_step(&totp, code.trim()) else {
+        return Ok(false);
+    };
+    let result = sqlx::query!(
+        "UPDATE users SET totp_last_step = $2
+        WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)",
+        user_id,
+        step
+    )
+    .execute(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    Ok(result.rows_affected() == 1)
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+async fn use_recovery_code(user_id: i32, code: &str, db: &Pool<Postgres>) -> Result<bool, AuthError> {
+    let result = sqlx::query!(
+        "UPDATE recovery_codes SET used_at = now()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+        user_id,
+        hash_token(&normalize_recovery_code(code))
+    )
+    .execute(db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?;
+    Ok(result.rows_affected() == 1)
+}
+
+// Accepts a 6 digit TOTP code or, when allowed, one of the recovery codes
+async fn verify_second_factor(user_id: i32, code: &str, allow_recovery: bool, db: &Pool<Postgres>) -> Result<bool, AuthError> {
+    let code = code.trim();
+    if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+        verify_totp(user_id, code, db).await
+    } else if allow_recovery {
+        use_recovery_code(user_id, code, db).await
+    } else {
+        Ok(false)
+    }
+}
+
+// Runs a second factor check under the same lockout rules as password logins
+pub async fn checked_second_factor(user_id: i32, code: &str, allow_recovery: bool, db: &Pool<Postgres>) -> Result<(), AuthError> {
+    let keys = [LimitKey::two_factor(user_id)];
+    check_attempts(&keys, db).await?;
+    if !verify_second_factor(user_id, code, allow_recovery, db).await? {
+        record_failure(&keys, db).await?;
+        return Err(AuthError::InvalidTwoFactorCode);
+    }
+    record_success(&keys[0], db).await
+}
+
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    OsRng.fill_bytes(&mut bytes);
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}", &hex[..5], &hex[5..])
+}
+
+pub async fn enroll(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>
+) -> Result<Json<EnrollResponse>, Response> {
+    log(HTTP, &format!("UserID <{}> requested ENROLL 2fa", user.user_id));
+    let account = sqlx::query!(
+        "SELECT username, totp_enabled FROM users WHERE id = $1",
+        user.user_id
+    )
+    .fetch_one(&db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    if account.totp_enabled {
+        return Err(AuthError::TwoFactorAlreadyEnabled.into_response());
+    }
+    let mut secret = vec![0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    let totp = build_totp(secret, &account.username).map_err(IntoResponse::into_response)?;
+    let secret = totp.get_secret_base32();
+    sqlx::query!(
+        "UPDATE users SET totp_secret = $2, totp_last_step = NULL WHERE id = $1",
+        user.user_id,
+        secret
+    )
+    .execute(&db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    Ok(Json(EnrollResponse { secret, otpauth_uri: totp.get_url() }))
+}
+
+pub async fn confirm(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    user: AuthUser<scope::Account>,
+    Json(payload): Json<ConfirmRequest>
+) -> Result<Json<ConfirmResponse>, Response> {
+    log(HTTP, &format!("UserID <{}> requested CONFIRM 2fa", user.user_id));
+    let enabled = sqlx::query_scalar!(
+        "SELECT totp_enabled FROM users WHERE id = $1",
+        user.user_id
+    )
+    .fetch_one(&db)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    if enabled {
+        return Err(AuthError::TwoFactorAlreadyEnabled.into_response());
+    }
+    // Recovery codes do not exist yet, the first code must come from the authenticator app
+    checked_second_factor(user.user_id, &payload.code, false, &db).await.map_err(IntoResponse::into_response)?;
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+    let hashes: Vec<String> = recovery_codes.iter().map(|c| hash_token(&normalize_recovery_code(c))).collect();
+    let mut tx = db.begin().await.map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    sqlx::query!("UPDATE users SET totp_enabled = true WHERE id = $1", user.user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user.user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    sqlx::query!(
+        "INSERT INTO recovery_codes (user_id, code_hash) SELECT $1, unnest($2::TEXT[])",
+        user.user_id,
+        &hashes
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    tx.commit().await.map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    audit::record(&db, &client, AuditEvent::TwoFactorEnabled, Some(user.user_id), json!({})).await;
+    Ok(Json(ConfirmResponse { recovery_codes }))
+}
+
+pub async fn disable(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    user: AuthUser<scope::Account>,
+    ValidJson(payload): ValidJson<DisableRequest>
+) -> Result<StatusCode, Response> {
+    log(HTTP, &format!("UserID <{}> requested DISABLE 2fa", user.user_id));
+    let totp_enabled = sqlx::query_scalar!("SELECT totp_enabled FROM users WHERE id = $1", user.user_id)
+        .fetch_one(&db)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    if !totp_enabled {
+        return Err(AuthError::TwoFactorNotEnrolled.into_response());
+    }
+    reauthenticate(user.user_id, user.session_id, &payload.password, true, Some(&payload.code), &db)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    let mut tx = db.begin().await.map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    sqlx::query!(
+        "UPDATE users SET totp_enabled = false, totp_secret = NULL, totp_last_step = NULL WHERE id = $1",
+        user.user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user.user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    tx.commit().await.map_err(|_| AuthError::DatabaseOperationFailed.into_response())?;
+    audit::record(&db, &client, AuditEvent::TwoFactorDisabled, Some(user.user_id), json!({})).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn verify(
+    State(db): State<Pool<Postgres>>,
+    client: ClientInfo,
+    Json(payload): Json<VerifyRequest>
+) -> Result<Json<VerifyResponse>, AuthError> {
+    let user_id = decode_challenge_jwt(&payload.challenge_token)?;
+    log(HTTP, &format!("UserID <{}> requested VERIFY 2fa", user_id));
+    if let Err(e) = checked_second_factor(user_id, &payload.code, true, &db).await {
+        let reason = match e {
+            AuthError::InvalidTwoFactorCode => Some("invalid_2fa_code"),
+            AuthError::TooManyAttempts(_) => Some("locked_out"),
+            _ => None
+        };
+        if let Some(reason) = reason {
+            let detail = json!({ "method": "2fa", "reason": reason });
+            audit::record(&db, &client, AuditEvent::LoginFailed, Some(user_id), detail).await;
+        }
+        return Err(e);
+    }
+    let session = open_session(user_id, "2fa", &client, &db).await?;
+    Ok(Json(VerifyResponse {
+        token: session.token,
+        refresh_token: session.refresh_token
+    }))
+}
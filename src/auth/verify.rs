@@ -0,0 +1,109 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use crate::api::access::scope;
+use crate::api::auth_user::AuthUser;
+use crate::api::api::ApiError;
+use crate::debug::{log, LogType::HTTP};
+use crate::mail::mailer::{app_url, send_mail, Mail};
+use super::auth::{identity_conflict, AuthError};
+use super::token::{generate_token, hash_token};
+
+// Verification links expire after a day
+const VERIFICATION_TOKEN_HOURS: i32 = 24;
+
+#[derive(Serialize, Deserialize)]
+pub struct VerifyRequest {
+    token: String
+}
+
+// Issues a fresh verification token for the given address and mails it out
+pub async fn send_verification(
+    user_id: i32,
+    username: &str,
+    email: &str,
+    db: &Pool<Postgres>
+) -> Result<(), sqlx::Error> {
+    let token = generate_token();
+    // Only the newest link works, so an old one cannot switch back to an address changed since
+    sqlx::query!(
+        "UPDATE email_verifications SET used_at = now() WHERE user_id = $1 AND used_at IS NULL",
+        user_id
+    )
+    .execute(db)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO email_verifications (user_id, email, token_hash, expires_at)
+        VALUES ($1, $2, $3, now() + make_interval(hours => $4))",
+        user_id,
+        email,
+        hash_token(&token),
+        VERIFICATION_TOKEN_HOURS
+    )
+    .execute(db)
+    .await?;
+    send_mail(Mail {
+        to: email.into(),
+        subject: "Verify your Stackture email".into(),
+        body: format!(
+            "Hi {},\n\nPlease confirm this email address for your Stackture account \
+            by opening the link below within {} hours:\n\n{}/verify-email?token={}",
+            username, VERIFICATION_TOKEN_HOURS, app_url(), token
+        )
+    });
+    Ok(())
+}
+
+pub async fn verify(
+    State(db): State<Pool<Postgres>>,
+    Json(payload): Json<VerifyRequest>
+) -> Result<StatusCode, AuthError> {
+    log(HTTP, "Anonymous user requested VERIFY email");
+    let mut tx = db.begin().await.map_err(|_| AuthError::DatabaseOperationFailed)?;
+    let verification = sqlx::query!(
+        "UPDATE email_verifications SET used_at = now()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+        RETURNING user_id, email",
+        hash_token(&payload.token)
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| AuthError::DatabaseOperationFailed)?
+    .ok_or(AuthError::InvalidVerificationToken)?;
+
+    // A token vouches for the address it was mailed to, which completes an email change
+    // when that address differs from the current one
+    sqlx::query!(
+        "UPDATE users SET email = $2, email_verified = true WHERE id = $1",
+        verification.user_id,
+        verification.email
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| identity_conflict(&e).unwrap_or(AuthError::DatabaseOperationFailed))?;
+
+    tx.commit().await.map_err(|_| AuthError::DatabaseOperationFailed)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn resend_verification(
+    State(db): State<Pool<Postgres>>,
+    user: AuthUser<scope::Account>
+) -> Result<StatusCode, ApiError> {
+    log(HTTP, &format!("UserID <{}> requested RESEND verification", user.user_id));
+    let account = sqlx::query!(
+        "SELECT username, email, email_verified FROM users WHERE id = $1",
+        user.user_id
+    )
+    .fetch_optional(&db)
+    .await
+    .map_err(|_| ApiError::DatabaseOperationFailed)?
+    .ok_or(ApiError::ItemNotFound)?;
+    if account.email_verified {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    send_verification(user.user_id, &account.username, &account.email, &db)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(StatusCode::ACCEPTED)
+}
@@ -1,10 +1,12 @@
 use axum::{extract::{Path, State}, Json};
-use axum_extra::{headers::{authorization::Bearer, Authorization}, TypedHeader};
 use sqlx::{Pool, Postgres};
 use crate::api::api::ApiError;
 use serde::{Serialize, Deserialize};
-use crate::api::api::extract_token_data;
-use super::db::{fetch_messages, fetch_chat_id, verify_user_workspace};
+use crate::api::access::scope;
+use crate::api::auth_user::AuthUser;
+use crate::api::member::{require_workspace_role, WorkspaceRole};
+use super::db::{fetch_messages, fetch_chat_id, fetch_transcripts};
+use super::node::ChatMessage;
 
 #[derive(Deserialize, Serialize)]
 pub struct Message {
@@ -14,40 +16,47 @@ pub struct Message {
 
 pub async fn fetch_chat(
     State(db): State<Pool<Postgres>>,
-    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+    user: AuthUser<scope::Chat>,
     Path((workspace_id, node_id)): Path<(i32, i32)>,
 ) -> Result<Json<Vec<Message>>, ApiError> {
-    match extract_token_data(auth) {
-        Ok(token_data) => {
-            if !verify_user_workspace(workspace_id, token_data.user_id, db.clone()).await {
-                return Err(ApiError::UnauthorizedAccess);
-            }
-        }
-        Err(e) => {
-            return Err(e);
-        }
-    }
+    require_workspace_role(workspace_id, user.user_id, WorkspaceRole::Viewer, &db).await?;
 
     if let Ok(chat_id) = fetch_chat_id(workspace_id, node_id, db.clone()).await {
         if let Ok(chats) = fetch_messages(chat_id, db).await {
-            let mut chat_responses: Vec<Message> = vec![];
-
-            for x in chats {
-                if let Some(_) = x.tool_calls {
-                    chat_responses.push(Message {
-                        message: "Here is the generated tree.".into(),
-                        is_user: false
-                    });
-                }
-                chat_responses.push(Message {
-                    message: x.content.unwrap_or("".into()),
-                    is_user: x.role == "user"
-                });
-            }
-
-            return Ok(Json(chat_responses));
+            return Ok(Json(transcript(chats)));
         }
     }
 
     return Err(ApiError::ItemNotFound);
-}
\ No newline at end of file
+}
+
+// What the user sees of a chat, tool calls become a note that the tree was generated
+fn transcript(messages: Vec<ChatMessage>) -> Vec<Message> {
+    let mut chat_responses: Vec<Message> = vec![];
+
+    for x in messages {
+        if let Some(_) = x.tool_calls {
+            chat_responses.push(Message {
+                message: "Here is the generated tree.".into(),
+                is_user: false
+            });
+        }
+        chat_responses.push(Message {
+            message: x.content.unwrap_or("".into()),
+            is_user: x.role == "user"
+        });
+    }
+
+    chat_responses
+}
+
+// Every chat of the workspace as the user sees it, keyed by node (None for the root chat)
+pub async fn workspace_transcripts(workspace_id: i32, db: &Pool<Postgres>) -> Result<Vec<(Option<i32>, Vec<Message>)>, ApiError> {
+    let transcripts = fetch_transcripts(workspace_id, db)
+        .await
+        .map_err(|_| ApiError::DatabaseOperationFailed)?;
+    Ok(transcripts
+        .into_iter()
+        .map(|(node_id, messages)| (node_id, transcript(messages)))
+        .collect())
+}
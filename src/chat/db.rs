@@ -1,6 +1,6 @@
 use std::{collections::{HashMap, VecDeque}, hash::Hash};
 
-use sqlx::{query_as, query_scalar, Error, Pool, Postgres, Transaction};
+use sqlx::{query, query_as, query_scalar, Error, Pool, Postgres, Transaction};
 use super::node::{ChatMessage, Node};
 
 
@@ -16,18 +16,6 @@ struct DBNode {
 }
 
 
-pub async  fn verify_user_workspace(workspace_id: i32, user_id: i32, db: Pool<Postgres>) -> bool {
-    // Validate that the user owns the workspace containing the root_id
-    query_scalar!(
-            "SELECT EXISTS (SELECT 1 FROM workspaces WHERE id = $1 AND user_id = $2)",
-            workspace_id,
-            user_id
-        )
-        .fetch_one(&db)
-        .await
-        .unwrap_or(Some(false)).unwrap_or(false)
-}
-
 pub async fn fetch_chat_id(workspace_id: i32, node_id: i32, db: Pool<Postgres>) -> Result<i32, ()> {
     let chat_id: Option<i32> = if node_id == 0 {
         query_scalar!(
@@ -129,6 +117,34 @@ pub async fn fetch_messages(chat_id: i32, db: Pool<Postgres>) -> Result<Vec<Chat
     Ok(messages_data)
 }
 
+// Every chat of the workspace in full (fetch_messages only keeps the context sent to the AI), None is the root chat
+pub async fn fetch_transcripts(workspace_id: i32, db: &Pool<Postgres>) -> Result<Vec<(Option<i32>, Vec<ChatMessage>)>, ()> {
+    let rows = query!(
+        "SELECT chats.id, chats.node_id, messages.message FROM chats
+        JOIN messages ON messages.chat_id = chats.id
+        WHERE chats.workspace_id = $1
+        ORDER BY chats.id, messages.sent_at, messages.id",
+        workspace_id
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|_| ())?;
+
+    let mut transcripts: Vec<(i32, Option<i32>, Vec<ChatMessage>)> = vec![];
+
+    for row in rows {
+        let Some(message) = row.message.and_then(|m| serde_json::from_str::<ChatMessage>(&m).ok()) else {
+            continue;
+        };
+        match transcripts.last_mut() {
+            Some((chat_id, _, messages)) if *chat_id == row.id => messages.push(message),
+            _ => transcripts.push((row.id, row.node_id, vec![message]))
+        }
+    }
+
+    Ok(transcripts.into_iter().map(|(_, node_id, messages)| (node_id, messages)).collect())
+}
+
 pub async fn workspace_tree_exists(workspace_id: i32, db: Pool<Postgres>) -> bool {
     let exists = query_scalar!(
         "SELECT root_id IS NOT NULL FROM workspaces WHERE id = $1;",
@@ -1,6 +1,9 @@
-use super::db::{fetch_chat_id, verify_user_workspace};
+use super::db::fetch_chat_id;
 use super::node::{node_chat, ChatAIResponse, Node};
-use crate::api::api::extract_token_data_str;
+use crate::api::access::scope;
+use crate::api::api::ApiError;
+use crate::api::auth_user::{AuthUser, OptionalAuthUser};
+use crate::api::member::{require_workspace_role, WorkspaceRole};
 use crate::debug::{errlog, LogType::SOCKET};
 use axum::{
     extract::{
@@ -18,7 +21,8 @@ use sqlx::{Pool, Postgres};
 struct ChatRequest {
     workspace_id: i32,
     node_id: i32,
-    token: String,
+    // Only needed when the upgrade request did not carry a token
+    token: Option<String>,
 }
 
 pub enum WebSocketResponse<'a> {
@@ -56,6 +60,7 @@ pub enum WebSocketError {
     UnauthorizedAccess,
     SessionCreationError,
     TokenError,
+    EmailNotVerified,
 }
 
 // !!! this might not be necessary anymore -> it is not used
@@ -69,11 +74,12 @@ pub fn create_socket_response(status: &str, message: &str) -> String {
 pub async fn websocket_listener(
     ws: WebSocketUpgrade,
     State(db): State<Pool<Postgres>>,
+    OptionalAuthUser(user): OptionalAuthUser<scope::Chat>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, db.clone()))
+    ws.on_upgrade(move |socket| handle_socket(socket, user, db.clone()))
 }
 
-async fn handle_socket(mut socket: WebSocket, db: Pool<Postgres>) {
+async fn handle_socket(mut socket: WebSocket, user: Option<AuthUser<scope::Chat>>, db: Pool<Postgres>) {
     let Some(Ok(msg)) = socket.recv().await else { return };
     let Message::Text(text) = msg else { return };
 
@@ -93,12 +99,21 @@ async fn handle_socket(mut socket: WebSocket, db: Pool<Postgres>) {
         }
     };
 
-    let success_token = match extract_token_data_str(request_data.token) {
-        Ok(token) => token,
-        Err(_) => {
+    let authenticated = match (user, &request_data.token) {
+        (Some(user), _) => Ok(user),
+        (None, Some(token)) => AuthUser::from_token(token, &db).await,
+        (None, None) => Err(ApiError::MissingToken)
+    };
+    let success_token = match authenticated {
+        Ok(user) => user,
+        Err(e) => {
+            let error = match e {
+                ApiError::EmailNotVerified => WebSocketError::EmailNotVerified,
+                _ => WebSocketError::TokenError
+            };
             socket
                 .send(
-                    WebSocketResponse::Error(WebSocketError::TokenError)
+                    WebSocketResponse::Error(error)
                         .into_message(),
                 )
                 .await
@@ -109,7 +124,8 @@ async fn handle_socket(mut socket: WebSocket, db: Pool<Postgres>) {
         }
     };
 
-    if !verify_user_workspace(request_data.workspace_id, success_token.user_id, db.clone()).await {
+    // Chatting grows the tree, so viewers can only read the chat through the API
+    if require_workspace_role(request_data.workspace_id, success_token.user_id, WorkspaceRole::Editor, &db).await.is_err() {
         socket
             .send(
                 WebSocketResponse::Error(WebSocketError::UnauthorizedAccess)
@@ -0,0 +1,12 @@
+use std::env;
+use std::str::FromStr;
+
+// Reads an optional setting from the environment, panicking on values that do not parse
+// so a typo in the .env is caught instead of silently falling back. The settings live in
+// LazyLocks that main forces at startup, so that is where the panic happens.
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("{} has an invalid value in the .env!", key)),
+        Err(_) => default
+    }
+}
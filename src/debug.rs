@@ -5,14 +5,18 @@ use color_print::cprintln;
 pub enum LogType {
     SETUP,
     HTTP,
-    SOCKET
+    SOCKET,
+    MAIL,
+    JOB
 }
 
 pub fn log(log_type: LogType, message: &str) {
     match log_type {
         LogType::SETUP => cprintln!("<yellow>{}</yellow><blue>[SETUP]</blue><green>[LOG]</green>: {}", now(), message),
         LogType::HTTP => cprintln!("<yellow>{}</yellow><cyan>[HTTP]</cyan><green>[LOG]</green>: {}", now(), message),
-        LogType::SOCKET => cprintln!("<yellow>{}</yellow><magenta>[SOCKET]</magenta><green>[LOG]</green>: {}", now(), message)
+        LogType::SOCKET => cprintln!("<yellow>{}</yellow><magenta>[SOCKET]</magenta><green>[LOG]</green>: {}", now(), message),
+        LogType::MAIL => cprintln!("<yellow>{}</yellow><blue>[MAIL]</blue><green>[LOG]</green>: {}", now(), message),
+        LogType::JOB => cprintln!("<yellow>{}</yellow><white>[JOB]</white><green>[LOG]</green>: {}", now(), message)
     }
 }
 
@@ -20,7 +24,9 @@ pub fn errlog(log_type: LogType, error: &impl Error) {
     match log_type {
         LogType::SETUP => cprintln!("<yellow>{}</yellow><yellow>[SETUP]</yellow><red>[ERROR]</red>: {}", now(), error),
         LogType::HTTP => cprintln!("<yellow>{}</yellow><cyan>[HTTP]</cyan><red>[ERROR]</red>: {}", now(), error),
-        LogType::SOCKET => cprintln!("<yellow>{}</yellow><magenta>[SOCKET]</magenta><red>[ERROR]</red>: {}", now(), error)
+        LogType::SOCKET => cprintln!("<yellow>{}</yellow><magenta>[SOCKET]</magenta><red>[ERROR]</red>: {}", now(), error),
+        LogType::MAIL => cprintln!("<yellow>{}</yellow><blue>[MAIL]</blue><red>[ERROR]</red>: {}", now(), error),
+        LogType::JOB => cprintln!("<yellow>{}</yellow><white>[JOB]</white><red>[ERROR]</red>: {}", now(), error)
     }
 }
 
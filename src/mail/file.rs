@@ -0,0 +1,31 @@
+use std::{env, fs, path::PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::debug::{log, LogType::MAIL};
+use super::mailer::{Mail, MailError, Mailer};
+
+// Writes every mail to MAIL_DIR and echoes it to the log, for local development without a mail server
+pub struct FileMailer {
+    dir: PathBuf
+}
+
+impl FileMailer {
+    pub fn from_env() -> FileMailer {
+        let dir = PathBuf::from(env::var("MAIL_DIR").unwrap_or("mail".into()));
+        fs::create_dir_all(&dir).expect("Failed to create MAIL_DIR!");
+        FileMailer { dir }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let path = self.dir.join(format!("{}-{}.eml", stamp, mail.to.replace(['/', '\\'], "_")));
+        let content = format!("To: {}\nSubject: {}\n\n{}\n", mail.to, mail.subject, mail.body);
+        fs::write(&path, &content).map_err(|e| MailError::DeliveryFailed(e.to_string()))?;
+        log(MAIL, &format!("Wrote mail to <{}>:\n{}", path.display(), content));
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use std::env;
+use std::error::Error;
+use std::sync::{Arc, LazyLock};
+use derive_more::Display;
+use dotenvy::dotenv;
+use crate::debug::{errlog, log, LogType::MAIL};
+use super::file::FileMailer;
+use super::smtp::SmtpMailer;
+
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String
+}
+
+#[derive(Debug, Display)]
+pub enum MailError {
+    #[display("InvalidAddress: {_0}")]
+    InvalidAddress(String),
+    #[display("DeliveryFailed: {_0}")]
+    DeliveryFailed(String)
+}
+
+impl Error for MailError {}
+
+// Anything that can deliver a plain-text mail, selected through MAILER in the .env
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+static MAILER: LazyLock<Arc<dyn Mailer>> = LazyLock::new(|| {
+    dotenv().expect("Failed to load environment variables!");
+    match env::var("MAILER").unwrap_or("file".into()).as_str() {
+        "smtp" => {
+            log(MAIL, "Using SMTP mailer");
+            Arc::new(SmtpMailer::from_env())
+        },
+        "file" => {
+            log(MAIL, "Using file mailer");
+            Arc::new(FileMailer::from_env())
+        },
+        other => panic!("Unknown MAILER <{}>, expected smtp or file!", other)
+    }
+});
+
+// Called at startup so an unknown MAILER stops the server instead of failing the first mail
+pub fn load_mailer() {
+    LazyLock::force(&MAILER);
+}
+
+// Delivers in the background so handlers never wait on (or leak timing through) the mail server
+pub fn send_mail(mail: Mail) {
+    let mailer = MAILER.clone();
+    tokio::task::spawn_blocking(move || {
+        match mailer.send(&mail) {
+            Ok(()) => log(MAIL, &format!("Sent <{}> to <{}>", mail.subject, mail.to)),
+            Err(e) => errlog(MAIL, &e)
+        }
+    });
+}
+
+// Base URL of the frontend, used to build links inside mails
+pub fn app_url() -> String {
+    env::var("APP_URL").unwrap_or("http://stackture.eloquenceprojects.org".into())
+}
@@ -0,0 +1,50 @@
+use std::env;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    Message, SmtpTransport, Transport,
+};
+use super::mailer::{Mail, MailError, Mailer};
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox
+}
+
+impl SmtpMailer {
+    pub fn from_env() -> SmtpMailer {
+        let host = env::var("SMTP_HOST").expect("SMTP_HOST must be set in the .env!");
+        let username = env::var("SMTP_USERNAME").expect("SMTP_USERNAME must be set in the .env!");
+        let password = env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD must be set in the .env!");
+        let from = env::var("MAIL_FROM")
+            .expect("MAIL_FROM must be set in the .env!")
+            .parse()
+            .expect("MAIL_FROM must be a valid mailbox!");
+        let mut builder = SmtpTransport::relay(&host)
+            .expect("Failed to configure SMTP relay!")
+            .credentials(Credentials::new(username, password));
+        if let Ok(port) = env::var("SMTP_PORT") {
+            builder = builder.port(port.parse().expect("SMTP_PORT must be a number!"));
+        }
+        SmtpMailer { transport: builder.build(), from }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let to: Mailbox = mail.to
+            .parse()
+            .map_err(|_| MailError::InvalidAddress(mail.to.clone()))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&mail.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(mail.body.clone())
+            .map_err(|e| MailError::DeliveryFailed(e.to_string()))?;
+        self.transport
+            .send(&message)
+            .map_err(|e| MailError::DeliveryFailed(e.to_string()))?;
+        Ok(())
+    }
+}
@@ -2,21 +2,41 @@ pub mod auth;
 pub mod db;
 pub mod api;
 pub mod chat;
+pub mod config;
 pub mod debug;
+pub mod mail;
 
 use std::net::SocketAddr;
 
-use api::workspace::{create_workspace, delete_workspace, fetch_workspaces, get_workspace};
-use auth::{login::login, register::register};
+use api::{admin, audit, export, group, import, member, progress, share, template, trash};
+use api::auth_user::{require_role, RoleGuard};
+use api::workspace::{clone_workspace, create_workspace, delete_workspace, fetch_workspaces, get_workspace, update_workspace};
+use auth::{
+    account,
+    keys::{jwks, load_keys},
+    limiter,
+    login::login,
+    logout::logout,
+    oidc,
+    password::{forgot_password, reset_password},
+    personal_token,
+    refresh::refresh,
+    two_factor,
+    register::register,
+    role::Role,
+    verify::{resend_verification, verify}
+};
 use axum::{
     http::header,
-    routing::{delete, get, post, put},
+    middleware,
+    routing::{delete, get, patch, post, put},
     Router
 };
 use chat::websocket::websocket_listener;
 use chat::api::fetch_chat;
 use db::postgres::connect_db;
 use debug::{log, LogType::SETUP};
+use mail::mailer::load_mailer;
 use sqlx::{Pool, Postgres};
 use tokio::net::TcpListener;
 use api::node;
@@ -27,7 +47,18 @@ async fn main() {
     
     log(SETUP, "Starting Stackture backend server...");
 
+    // Read every setting now, so a bad value in the .env stops the server before it serves anything
+    load_keys();
+    load_mailer();
+    limiter::load_config();
+    auth::auth::load_hash_params();
+    api::validation::load_password_policy();
+    api::access::load_restrictions();
+    api::client::load_proxy_config();
+    oidc::load_providers();
     let db_pool = connect_db().await;
+    trash::spawn_purge_job(db_pool.clone());
+    limiter::spawn_cleanup_job(db_pool.clone());
 
     let node_handler: Router<Pool<Postgres>> = Router::new()
         .route("/create", post(node::create))
@@ -43,16 +74,74 @@ async fn main() {
         .route("/get/{id}", get(get_workspace))
         .route("/delete/{id}", delete(delete_workspace))
         .route("/fetch", get(fetch_workspaces))
+        .route("/import", post(import::import_workspace))
+        .route("/trash", get(trash::list))
+        .route("/trash/{id}", delete(trash::purge))
+        .route("/{id}/restore", post(trash::restore))
+        .route("/{id}", patch(update_workspace))
+        .route("/{id}/clone", post(clone_workspace))
+        .route("/{id}/export", get(export::export_workspace))
+        .route("/{id}/progress", get(progress::workspace_progress))
+        .route("/invites", get(member::invites))
+        .route("/{id}/members", get(member::list).post(member::invite))
+        .route("/{id}/members/{user_id}", put(member::update).delete(member::remove))
+        .route("/{id}/accept", post(member::accept))
+        .route("/{id}/shares", get(share::list).post(share::create))
+        .route("/{id}/shares/{share_id}", delete(share::revoke))
+        .with_state(db_pool.clone());
+
+    let template_handler: Router<Pool<Postgres>> = Router::new()
+        .route("/", get(template::list).post(template::create))
+        .route("/{id}", get(template::get).delete(template::delete))
+        .route("/{id}/instantiate", post(template::instantiate))
+        .with_state(db_pool.clone());
+
+    let group_handler: Router<Pool<Postgres>> = Router::new()
+        .route("/", get(group::list).post(group::create))
+        .route("/{id}", delete(group::delete))
+        .route("/{id}/members", get(group::members).post(group::add_member))
+        .route("/{id}/members/{user_id}", delete(group::remove_member))
         .with_state(db_pool.clone());
 
     let api_handler: Router<Pool<Postgres>> = Router::new()
         .nest("/workspace", workspace_handler)
         .nest("/node", node_handler)
+        .nest("/templates", template_handler)
+        .nest("/groups", group_handler)
         .with_state(db_pool.clone());
 
     let auth_handler: Router<Pool<Postgres>> = Router::new()
         .route("/login", post(login))
         .route("/register", post(register))
+        .route("/refresh", post(refresh))
+        .route("/logout", post(logout))
+        .route("/password/forgot", post(forgot_password))
+        .route("/password/reset", post(reset_password))
+        .route("/password/change", post(account::change_password))
+        .route("/email/change", post(account::change_email))
+        .route("/me", get(account::me).delete(account::delete_account))
+        .route("/verify", post(verify))
+        .route("/verify/resend", post(resend_verification))
+        .route("/2fa/enroll", post(two_factor::enroll))
+        .route("/2fa/confirm", post(two_factor::confirm))
+        .route("/2fa/disable", post(two_factor::disable))
+        .route("/2fa/verify", post(two_factor::verify))
+        .route("/oidc/{provider}", get(oidc::authorize))
+        .route("/oidc/{provider}/callback", get(oidc::callback))
+        .route("/tokens", get(personal_token::list).post(personal_token::create))
+        .route("/tokens/{id}", delete(personal_token::revoke))
+        .route("/audit", get(audit::my_events))
+        .with_state(db_pool.clone());
+
+    let admin_handler: Router<Pool<Postgres>> = Router::new()
+        .route("/users", get(admin::list_users))
+        .route("/users/{id}/usage", get(admin::user_usage))
+        .route("/users/{id}/role", put(admin::set_role))
+        .route("/users/{id}/disable", post(admin::disable_user))
+        .route("/users/{id}/enable", post(admin::enable_user))
+        .route("/stats", get(admin::stats))
+        .route("/audit", get(admin::audit_events))
+        .route_layer(middleware::from_fn_with_state(RoleGuard::new(db_pool.clone(), Role::Admin), require_role))
         .with_state(db_pool.clone());
 
     let http_server: Router = Router::new()
@@ -60,10 +149,13 @@ async fn main() {
         // .route("/about", todo!())
         // .route("/dashboard", todo!())
         // .route("/workspace", todo!())
+        .route("/.well-known/jwks.json", get(jwks))
         .route("/chat", get(websocket_listener))
         .route("/chat/fetch/{workspace_id}/{node_id}", get(fetch_chat))
+        .route("/share/{token}", get(share::view))
         .nest("/auth", auth_handler)
         .nest("/api", api_handler)
+        .nest("/admin", admin_handler)
         .with_state(db_pool.clone())
 	.layer(CorsLayer::new()
 	.allow_headers(Any)
@@ -72,7 +164,9 @@ async fn main() {
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
 
     let listener = TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, http_server).await.expect("Failed to start backend server!");
+    axum::serve(listener, http_server.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .expect("Failed to start backend server!");
 
 }
 